@@ -0,0 +1,140 @@
+//! `dmarc-milter`: milter enforcing DMARC for Postfix or Sendmail
+use clap::Parser;
+use dmarc::milter::DmarcMilter;
+use dmarc::policyd::PolicyServer;
+use dmarc::{CacheOptions, DmarcVerifier, PolicyCache, ReceivedSpfHeader};
+use std::process::ExitCode;
+use std::sync::Arc;
+use trust_dns_resolver::TokioAsyncResolver;
+
+#[derive(Parser)]
+#[command(
+    name = "dmarc-milter",
+    version,
+    about = "Milter enforcing DMARC (RFC7489)"
+)]
+struct Cli {
+    /// Address to listen on: `host:port`, `inet:port@host` or `unix:path`
+    #[arg(long, default_value = "127.0.0.1:8891")]
+    listen: String,
+    /// Identifier of the Authentication-Results header, usually the host name
+    /// of the MTA
+    #[arg(long, default_value = "localhost")]
+    authserv_id: String,
+    /// Only add the Authentication-Results header, without quarantining or
+    /// rejecting messages
+    #[arg(long)]
+    monitor: bool,
+    /// Read the SPF result from the first Received-SPF header instead of
+    /// evaluating SPF. The MTA must check SPF and add its own Received-SPF
+    /// header before calling the milter, otherwise a header forged by the
+    /// sender is trusted.
+    #[arg(long)]
+    trust_received_spf: bool,
+    /// Also serve the Postfix policy delegation protocol on this address, to
+    /// discover the policy of the envelope sender domain before the data.
+    /// The policies are cached and shared with the milter.
+    #[arg(long)]
+    policy_listen: Option<String>,
+}
+
+/// Converts the Sendmail socket syntax `inet:port@host` to `host:port`
+fn tcp_address(listen: &str) -> String {
+    match listen.strip_prefix("inet:") {
+        Some(addr) => match addr.split_once('@') {
+            Some((port, host)) => format!("{}:{}", host, port),
+            None => format!("0.0.0.0:{}", addr),
+        },
+        None => listen.to_owned(),
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), String> {
+    let resolver = TokioAsyncResolver::tokio_from_system_conf()
+        .map_err(|err| format!("failed to create DNS resolver: {}", err))?;
+    let cache = PolicyCache::new(
+        dmarc::dns::from_tokio_resolver(resolver),
+        CacheOptions::default(),
+    );
+
+    // DKIM, SPF and DMARC all share the cache, the milter and the policy
+    // server share the verifier
+    let mut verifier = DmarcVerifier::new(Arc::new(cache));
+    if cli.trust_received_spf {
+        verifier.spf_source = Arc::new(ReceivedSpfHeader);
+    }
+    let verifier = Arc::new(verifier);
+    let policy_server = Arc::new(PolicyServer::new(verifier.clone()));
+    let mut milter = DmarcMilter::new(verifier, &cli.authserv_id);
+    milter.enforce = !cli.monitor;
+    let milter = Arc::new(milter);
+
+    let milter_task = async {
+        match Listener::bind(&cli.listen).await? {
+            Listener::Tcp(listener) => milter.serve(listener).await,
+            #[cfg(unix)]
+            Listener::Unix(listener) => milter.serve_unix(listener).await,
+        }
+        .map_err(|err| err.to_string())
+    };
+    let policy_task = async {
+        let addr = match &cli.policy_listen {
+            Some(addr) => addr,
+            None => return futures::future::pending().await,
+        };
+        match Listener::bind(addr).await? {
+            Listener::Tcp(listener) => policy_server.clone().serve(listener).await,
+            #[cfg(unix)]
+            Listener::Unix(listener) => policy_server.clone().serve_unix(listener).await,
+        }
+        .map_err(|err| err.to_string())
+    };
+    tokio::try_join!(milter_task, policy_task).map(|_| ())
+}
+
+enum Listener {
+    Tcp(tokio::net::TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl Listener {
+    async fn bind(listen: &str) -> Result<Self, String> {
+        #[cfg(unix)]
+        if let Some(path) = listen.strip_prefix("unix:") {
+            // Remove the socket left by a previous run
+            let _ = std::fs::remove_file(path);
+            return tokio::net::UnixListener::bind(path)
+                .map(Listener::Unix)
+                .map_err(|err| format!("failed to listen on {}: {}", listen, err));
+        }
+
+        tokio::net::TcpListener::bind(tcp_address(listen))
+            .await
+            .map(Listener::Tcp)
+            .map_err(|err| format!("failed to listen on {}: {}", listen, err))
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run(Cli::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tcp_address() {
+        assert_eq!(tcp_address("127.0.0.1:8891"), "127.0.0.1:8891");
+        assert_eq!(tcp_address("inet:8891@localhost"), "localhost:8891");
+        assert_eq!(tcp_address("inet:8891"), "0.0.0.0:8891");
+    }
+}
@@ -0,0 +1,209 @@
+//! `dmarc eval`
+use crate::DnsArgs;
+use clap::Args;
+use dmarc::{DmarcVerifier, Envelope, EvaluationTrace, NoSpf, SPFResult, SpfSource};
+use futures::future::BoxFuture;
+use serde_json::json;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+#[derive(Args)]
+pub struct EvalArgs {
+    /// Message in the RFC5322 format (.eml)
+    message: PathBuf,
+    /// IP address of the SMTP client
+    #[arg(long)]
+    ip: Option<IpAddr>,
+    /// Domain of the SMTP HELO/EHLO command
+    #[arg(long)]
+    helo: Option<String>,
+    /// Address of the SMTP MAIL FROM command
+    #[arg(long)]
+    mail_from: Option<String>,
+    /// SPF result (pass, fail, softfail, neutral, none, temperror or
+    /// permerror) for the MAIL FROM domain, or the HELO domain if empty.
+    /// Evaluated from --ip, --helo and --mail-from if not provided.
+    #[arg(long)]
+    spf: Option<String>,
+    /// Identifier of the Authentication-Results header
+    #[arg(long, default_value = "localhost")]
+    authserv_id: String,
+    /// Print the result and the decision trace as JSON
+    #[arg(long)]
+    json: bool,
+    #[command(flatten)]
+    dns: DnsArgs,
+}
+
+/// SPF result given on the command line
+struct FixedSpf(String);
+
+impl SpfSource for FixedSpf {
+    fn spf_result<'a>(
+        &'a self,
+        envelope: &'a Envelope<'a>,
+        _raw_message: &'a [u8],
+    ) -> BoxFuture<'a, Option<SPFResult>> {
+        let result = SPFResult {
+            domain_used: envelope.spf_domain(),
+            value: self.0.clone(),
+        };
+        Box::pin(async move { Some(result) })
+    }
+}
+
+pub async fn run(args: EvalArgs) -> Result<(), String> {
+    let logger = crate::logger();
+    let raw = std::fs::read(&args.message)
+        .map_err(|err| format!("failed to read {:?}: {}", args.message, err))?;
+
+    let resolver = dmarc::dns::from_tokio_resolver(args.dns.tokio_resolver()?);
+    let mut verifier = DmarcVerifier::new(resolver);
+    match (&args.spf, &args.ip) {
+        (Some(spf), _) => verifier.spf_source = Arc::new(FixedSpf(spf.clone())),
+        // SPF is evaluated by the default source
+        (None, Some(_)) => {}
+        (None, None) => verifier.spf_source = Arc::new(NoSpf),
+    }
+
+    let verdict = verifier
+        .verify_message(
+            &logger,
+            &raw,
+            args.ip,
+            args.helo.as_deref().unwrap_or_default(),
+            args.mail_from.as_deref().unwrap_or_default(),
+        )
+        .await
+        .map_err(|err| err.to_string())?;
+    let methods = verdict.authentication_methods();
+    let header = format!("{}; {}", args.authserv_id, methods.join("; "));
+
+    if args.json {
+        let spf = verdict.spf_result.as_ref().map(
+            |spf_result| json!({ "domain": spf_result.domain_used, "result": spf_result.value }),
+        );
+        let value = json!({
+            "from_domains": verdict.from_domains,
+            "spf": spf,
+            "traces": verdict.traces,
+            "result": verdict.result.to_str(),
+            "disposition": verdict.disposition(),
+            "authentication_results": header,
+        });
+        println!("{}", serde_json::to_string_pretty(&value).unwrap());
+        return Ok(());
+    }
+
+    if let Some(ip) = &args.ip {
+        println!("Client IP:   {}", ip);
+    }
+    if let Some(helo) = &args.helo {
+        println!("HELO:        {}", helo);
+    }
+    if let Some(mail_from) = &args.mail_from {
+        println!("MAIL FROM:   {}", mail_from);
+    }
+    println!("From domain: {}", verdict.from_domains.join(", "));
+    match &verdict.spf_result {
+        Some(spf_result) => println!(
+            "SPF:         {} ({})",
+            spf_result.value, spf_result.domain_used
+        ),
+        None => println!("SPF:         none (not evaluated, use --ip or --spf)"),
+    }
+    for (trace, dkim_result) in verdict.traces.iter().zip(&verdict.dkim_results) {
+        print_trace(trace, dkim_result);
+    }
+    println!();
+    println!(
+        "Result:      {} (disposition {})",
+        verdict.result.to_str(),
+        verdict.disposition().to_str()
+    );
+    println!();
+    println!(
+        "Authentication-Results: {};\n\t{}",
+        args.authserv_id,
+        methods.join(";\n\t")
+    );
+
+    Ok(())
+}
+
+fn print_trace(trace: &EvaluationTrace, dkim_result: &cfdkim::DKIMResult) {
+    println!();
+    println!("[{}]", trace.from_domain);
+    println!(
+        "  DKIM:            {} (d={})",
+        dkim_result.with_detail(),
+        dkim_result.domain_used()
+    );
+
+    let policy = match (&trace.policy, &trace.policy_domain) {
+        (Some(policy), Some(policy_domain)) => {
+            let origin = if trace.overridden {
+                " (local override)"
+            } else if trace.from_psd {
+                " (public suffix domain)"
+            } else {
+                ""
+            };
+            println!("  Policy:          _dmarc.{}{}", policy_domain, origin);
+            policy
+        }
+        _ => {
+            println!("  Policy:          none found");
+            return;
+        }
+    };
+    println!("  Org. domain:     {}", trace.organizational_domain);
+    if let Some(exists) = trace.from_domain_exists {
+        println!("  Domain exists:   {}", exists);
+    }
+    println!(
+        "  Action:          {}{}{}",
+        policy.action.to_str(),
+        if policy.np_applied { " (np)" } else { "" },
+        if policy.testing {
+            " (testing mode)"
+        } else {
+            ""
+        }
+    );
+    println!(
+        "  Sampling:        pct={} {}",
+        policy.sampling.pct,
+        if policy.sampling.applied {
+            "applied"
+        } else {
+            "not applied"
+        }
+    );
+    for (name, alignment) in [("DKIM", &policy.dkim), ("SPF", &policy.spf)] {
+        if let Some(alignment) = alignment {
+            println!(
+                "  {} alignment:{} {} ({:?}: {} [{}] vs {} [{}]), result {}",
+                name,
+                " ".repeat(5 - name.len()),
+                if alignment.aligned {
+                    "aligned"
+                } else {
+                    "not aligned"
+                },
+                alignment.mode,
+                alignment.from_domain,
+                alignment.from_org_domain.as_deref().unwrap_or("-"),
+                alignment.domain,
+                alignment.org_domain.as_deref().unwrap_or("-"),
+                alignment.result
+            );
+        }
+    }
+    println!(
+        "  DMARC:           {} (disposition {})",
+        policy.result,
+        policy.disposition.to_str()
+    );
+}
@@ -0,0 +1,315 @@
+//! `dmarc lookup`
+use crate::DnsArgs;
+use clap::Args;
+use dmarc::{Alignement, Discovery, DiscoveryMode, DiscoveryOptions, Policy, PublicSuffixDomain};
+use serde_json::{json, Value};
+
+/// Tags whose effective value is displayed, in the order of the record
+const TAGS: &[&str] = &[
+    "p", "sp", "np", "psd", "t", "adkim", "aspf", "pct", "fo", "ri", "rua", "ruf",
+];
+
+#[derive(Args)]
+pub struct LookupArgs {
+    /// Domain of the RFC5322.From header
+    domain: String,
+    /// Use the DMARCbis DNS tree walk instead of the Public Suffix List
+    #[arg(long)]
+    tree_walk: bool,
+    /// Print the result as JSON
+    #[arg(long)]
+    json: bool,
+    #[command(flatten)]
+    dns: DnsArgs,
+}
+
+pub async fn run(args: LookupArgs) -> Result<(), String> {
+    let options = DiscoveryOptions {
+        mode: if args.tree_walk {
+            DiscoveryMode::TreeWalk
+        } else {
+            DiscoveryMode::PublicSuffixList
+        },
+        ..Default::default()
+    };
+    let discovery = dmarc::discover_policy_with_resolver(
+        args.dns.resolver()?,
+        &crate::logger(),
+        &args.domain,
+        &options,
+    )
+    .await
+    .map_err(|err| err.to_string())?;
+
+    if args.json {
+        let value = to_json(&args.domain, &discovery);
+        println!("{}", serde_json::to_string_pretty(&value).unwrap());
+    } else {
+        print_text(&args.domain, &discovery);
+    }
+    Ok(())
+}
+
+/// Where the policy was found, relative to the domain
+fn location(domain: &str, discovery: &Discovery) -> &'static str {
+    match &discovery.policy_domain {
+        None => "none",
+        Some(_) if discovery.from_psd => "public suffix domain",
+        Some(d) if d.eq_ignore_ascii_case(domain) => "exact domain",
+        Some(d) if d == &discovery.organizational_domain => "organizational domain",
+        Some(_) => "parent domain",
+    }
+}
+
+/// Returns the tags with their effective value, written as in a record, and
+/// whether they were present in the record, `None` if the policy wasn't
+/// parsed from a record
+fn effective_tags(policy: &Policy) -> Vec<(&'static str, String, Option<bool>)> {
+    let uris = |uris: &[dmarc::ReportUri]| {
+        uris.iter()
+            .map(|uri| match uri.max_size {
+                Some(size) => format!("{} (max {} bytes)", uri.uri, size),
+                None => uri.uri.clone(),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let alignement = |mode: &Alignement| match mode {
+        Alignement::Relaxed => "r",
+        Alignement::Strict => "s",
+    };
+
+    TAGS.iter()
+        .map(|&name| {
+            let value = match name {
+                "p" => policy.p.to_str().to_owned(),
+                "sp" => match &policy.sp {
+                    Some(sp) => sp.to_str().to_owned(),
+                    None => "(same as p)".to_owned(),
+                },
+                "np" => match &policy.np {
+                    Some(np) => np.to_str().to_owned(),
+                    None => "(same as sp)".to_owned(),
+                },
+                "psd" => match policy.psd {
+                    PublicSuffixDomain::Yes => "y",
+                    PublicSuffixDomain::No => "n",
+                    PublicSuffixDomain::Unknown => "u",
+                }
+                .to_owned(),
+                "t" => if policy.testing { "y" } else { "n" }.to_owned(),
+                "adkim" => alignement(&policy.adkim).to_owned(),
+                "aspf" => alignement(&policy.aspf).to_owned(),
+                "pct" => policy.pct.to_string(),
+                "fo" => policy.fo.clone(),
+                "ri" => policy.ri.to_string(),
+                "rua" => uris(&policy.rua),
+                "ruf" => uris(&policy.ruf),
+                _ => unreachable!(),
+            };
+            let present = (!policy.tags.is_empty()).then(|| policy.tag(name).is_some());
+            (name, value, present)
+        })
+        .collect()
+}
+
+fn print_text(domain: &str, discovery: &Discovery) {
+    println!("Domain:                {}", domain);
+    println!("Organizational domain: {}", discovery.organizational_domain);
+
+    let policy = match (&discovery.policy, &discovery.policy_domain) {
+        (Some(policy), Some(policy_domain)) => {
+            println!(
+                "Policy found at:       _dmarc.{} ({})",
+                policy_domain,
+                location(domain, discovery)
+            );
+            policy
+        }
+        _ => {
+            println!("Policy found at:       no DMARC policy found");
+            print_discovery_diagnostics(discovery);
+            return;
+        }
+    };
+    println!("Record:                {}", policy.record);
+    println!("Action:                {}", policy.action.to_str());
+    if policy.repaired {
+        println!("                       (invalid p or sp, treated as p=none)");
+    }
+
+    println!();
+    println!("Tags:");
+    for (name, value, explicit) in effective_tags(policy) {
+        let origin = if explicit == Some(false) {
+            " (default)"
+        } else {
+            ""
+        };
+        println!("  {:<6} {}{}", name, value, origin);
+    }
+    for tag in &policy.tags {
+        if tag.name != "v" && !TAGS.contains(&tag.name.as_str()) {
+            println!("  {:<6} {} (unknown)", tag.name, tag.raw_value);
+        }
+    }
+
+    let diagnostics = dmarc::lint_record(&policy.record);
+    if !diagnostics.is_empty() {
+        println!();
+        println!("Diagnostics:");
+        for diagnostic in diagnostics {
+            println!(
+                "  {:?} at {}..{}: {}",
+                diagnostic.severity, diagnostic.span.start, diagnostic.span.end, diagnostic.message
+            );
+        }
+    }
+    print_discovery_diagnostics(discovery);
+}
+
+fn print_discovery_diagnostics(discovery: &Discovery) {
+    for diagnostic in &discovery.diagnostics {
+        match diagnostic {
+            dmarc::DiscoveryDiagnostic::MultipleRecords { domain, count } => {
+                println!("Ignored {} DMARC records at _dmarc.{}", count, domain)
+            }
+            dmarc::DiscoveryDiagnostic::InvalidRecord { domain, error } => {
+                println!("Invalid DMARC record at _dmarc.{}: {}", domain, error)
+            }
+        }
+    }
+}
+
+/// The diagnostics and actions are serialized as by the library, the tag
+/// values are written as in a record
+fn to_json(domain: &str, discovery: &Discovery) -> Value {
+    let policy = discovery.policy.as_ref().map(|policy| {
+        let tags: serde_json::Map<String, Value> = effective_tags(policy)
+            .into_iter()
+            .map(|(name, value, explicit)| {
+                (
+                    name.to_owned(),
+                    json!({ "value": value, "default": explicit.map(|explicit| !explicit) }),
+                )
+            })
+            .collect();
+        let unknown_tags: serde_json::Map<String, Value> = policy
+            .tags
+            .iter()
+            .filter(|tag| tag.name != "v" && !TAGS.contains(&tag.name.as_str()))
+            .map(|tag| (tag.name.clone(), json!(tag.raw_value)))
+            .collect();
+        json!({
+            "record": policy.record,
+            "action": policy.action,
+            "repaired": policy.repaired,
+            "tags": tags,
+            "unknown_tags": unknown_tags,
+            "diagnostics": dmarc::lint_record(&policy.record),
+        })
+    });
+
+    json!({
+        "domain": domain,
+        "organizational_domain": discovery.organizational_domain,
+        "policy_domain": discovery.policy_domain,
+        "location": location(domain, discovery),
+        "policy": policy,
+        "discovery_diagnostics": discovery.diagnostics,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dmarc::{PolicyBuilder, ReceiverAction};
+
+    #[test]
+    fn test_location() {
+        let mut discovery = Discovery {
+            policy: Some(Policy::new(ReceiverAction::None)),
+            policy_domain: Some("example.com".to_owned()),
+            organizational_domain: "example.com".to_owned(),
+            from_psd: false,
+            overridden: false,
+            diagnostics: vec![],
+        };
+        assert_eq!(location("example.com", &discovery), "exact domain");
+        assert_eq!(
+            location("a.example.com", &discovery),
+            "organizational domain"
+        );
+
+        discovery.policy_domain = None;
+        assert_eq!(location("a.example.com", &discovery), "none");
+    }
+
+    #[test]
+    fn test_effective_tags() {
+        let policy = PolicyBuilder::new(ReceiverAction::Reject)
+            .pct(50)
+            .rua("mailto:a@example.com", Some(1024))
+            .build()
+            .unwrap();
+        // Without a record the presence of the tags is unknown
+        let tags = effective_tags(&policy);
+        assert_eq!(tags.len(), TAGS.len());
+        assert!(tags.contains(&("p", "reject".to_owned(), None)));
+        assert!(tags.contains(&("pct", "50".to_owned(), None)));
+        assert!(tags.contains(&(
+            "rua",
+            "mailto:a@example.com (max 1024 bytes)".to_owned(),
+            None
+        )));
+        assert!(tags.contains(&("sp", "(same as p)".to_owned(), None)));
+
+        // With a record only the published tags are present
+        let mut policy = policy;
+        policy.tags = [("v", "DMARC1"), ("p", "reject"), ("pct", "50")]
+            .into_iter()
+            .map(|(name, value)| dmarc::Tag {
+                name: name.to_owned(),
+                value: value.to_owned(),
+                raw_value: value.to_owned(),
+            })
+            .collect();
+        let tags = effective_tags(&policy);
+        assert!(tags.contains(&("p", "reject".to_owned(), Some(true))));
+        assert!(tags.contains(&("pct", "50".to_owned(), Some(true))));
+        assert!(tags.contains(&("adkim", "r".to_owned(), Some(false))));
+        assert!(tags.contains(&("psd", "u".to_owned(), Some(false))));
+    }
+
+    #[test]
+    fn test_to_json() {
+        let record = "v=DMARC1; p=reject; adkim=s; pct=200";
+        let mut policy = PolicyBuilder::new(ReceiverAction::Reject)
+            .adkim(Alignement::Strict)
+            .build()
+            .unwrap();
+        policy.record = record.to_owned();
+        let discovery = Discovery {
+            policy: Some(policy),
+            policy_domain: Some("example.com".to_owned()),
+            organizational_domain: "example.com".to_owned(),
+            from_psd: false,
+            overridden: false,
+            diagnostics: vec![dmarc::DiscoveryDiagnostic::MultipleRecords {
+                domain: "example.com".to_owned(),
+                count: 2,
+            }],
+        };
+        let json = to_json("example.com", &discovery);
+
+        // Same format as the serde serialization of the library types
+        assert_eq!(json["policy"]["action"], "reject");
+        assert_eq!(json["policy"]["tags"]["adkim"]["value"], "s");
+        assert_eq!(
+            json["policy"]["diagnostics"],
+            serde_json::to_value(dmarc::lint_record(record)).unwrap()
+        );
+        assert_eq!(json["discovery_diagnostics"][0]["kind"], "multiple_records");
+    }
+}
@@ -0,0 +1,82 @@
+//! `dmarc` command-line tool
+use clap::{Args, Parser, Subcommand};
+use std::net::{IpAddr, SocketAddr};
+use std::process::ExitCode;
+use std::sync::Arc;
+use trust_dns_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use trust_dns_resolver::TokioAsyncResolver;
+
+mod eval;
+mod lookup;
+mod report;
+
+#[derive(Parser)]
+#[command(name = "dmarc", version, about = "DMARC (RFC7489) tools")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Discover the DMARC policy of a domain
+    Lookup(lookup::LookupArgs),
+    /// Evaluate DMARC for a message
+    Eval(eval::EvalArgs),
+    /// Aggregate reports tools
+    #[command(subcommand)]
+    Report(report::ReportCommand),
+}
+
+#[derive(Args)]
+pub struct DnsArgs {
+    /// Nameserver to query (`ip` or `ip:port`) instead of the system's
+    /// configuration
+    #[arg(long)]
+    resolver: Option<String>,
+}
+
+impl DnsArgs {
+    pub fn resolver(&self) -> Result<Arc<dyn dmarc::dns::Lookup>, String> {
+        Ok(dmarc::dns::from_tokio_resolver(self.tokio_resolver()?))
+    }
+
+    pub fn tokio_resolver(&self) -> Result<TokioAsyncResolver, String> {
+        match &self.resolver {
+            Some(addr) => {
+                let addr = addr
+                    .parse::<SocketAddr>()
+                    .or_else(|_| addr.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+                    .map_err(|_| format!("invalid resolver address: {}", addr))?;
+                let nameservers =
+                    NameServerConfigGroup::from_ips_clear(&[addr.ip()], addr.port(), true);
+                let config = ResolverConfig::from_parts(None, vec![], nameservers);
+                Ok(TokioAsyncResolver::tokio(config, ResolverOpts::default()))
+            }
+            None => TokioAsyncResolver::tokio_from_system_conf()
+                .map_err(|err| format!("failed to create DNS resolver: {}", err)),
+        }
+    }
+}
+
+pub fn logger() -> slog::Logger {
+    slog::Logger::root(slog::Discard, slog::o!())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let res = match cli.command {
+        Command::Lookup(args) => lookup::run(args).await,
+        Command::Eval(args) => eval::run(args).await,
+        Command::Report(command) => report::run(command),
+    };
+
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,307 @@
+//! `dmarc report`
+use clap::{Args, Subcommand, ValueEnum};
+use dmarc::report::AggregateReport;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Subcommand)]
+pub enum ReportCommand {
+    /// Summarize aggregate reports (.xml or .xml.gz)
+    Summarize(SummarizeArgs),
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Table,
+    Csv,
+    Json,
+}
+
+#[derive(Args)]
+pub struct SummarizeArgs {
+    /// Report files or directories containing reports
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+    #[arg(long, value_enum, default_value_t = Format::Table)]
+    format: Format,
+}
+
+/// Message counts of a group of report records
+#[derive(Debug, PartialEq, Default, Clone)]
+struct Counts {
+    messages: u64,
+    pass: u64,
+    fail: u64,
+    dkim_unaligned: u64,
+    spf_unaligned: u64,
+    none: u64,
+    quarantine: u64,
+    reject: u64,
+}
+
+const COLUMNS: &[&str] = &[
+    "messages",
+    "pass",
+    "fail",
+    "dkim_unaligned",
+    "spf_unaligned",
+    "none",
+    "quarantine",
+    "reject",
+];
+
+impl Counts {
+    fn add(&mut self, record: &dmarc::report::ReportRecord) {
+        let count = record.row.count;
+        let evaluated = &record.row.policy_evaluated;
+
+        self.messages += count;
+        if evaluated.passed() {
+            self.pass += count;
+        } else {
+            self.fail += count;
+        }
+        if evaluated.dkim != "pass" {
+            self.dkim_unaligned += count;
+        }
+        if evaluated.spf != "pass" {
+            self.spf_unaligned += count;
+        }
+        match evaluated.disposition.as_str() {
+            "quarantine" => self.quarantine += count,
+            "reject" => self.reject += count,
+            _ => self.none += count,
+        }
+    }
+
+    fn values(&self) -> [u64; 8] {
+        [
+            self.messages,
+            self.pass,
+            self.fail,
+            self.dkim_unaligned,
+            self.spf_unaligned,
+            self.none,
+            self.quarantine,
+            self.reject,
+        ]
+    }
+}
+
+#[derive(Debug, Default)]
+struct Summary {
+    reports: usize,
+    by_source_ip: BTreeMap<String, Counts>,
+    by_reporter: BTreeMap<String, Counts>,
+}
+
+impl Summary {
+    fn add(&mut self, report: &AggregateReport) {
+        self.reports += 1;
+        for record in &report.records {
+            self.by_source_ip
+                .entry(record.row.source_ip.clone())
+                .or_default()
+                .add(record);
+            self.by_reporter
+                .entry(report.report_metadata.org_name.clone())
+                .or_default()
+                .add(record);
+        }
+    }
+
+    fn groups(&self) -> [(&'static str, &BTreeMap<String, Counts>); 2] {
+        [
+            ("source_ip", &self.by_source_ip),
+            ("reporter", &self.by_reporter),
+        ]
+    }
+}
+
+pub fn run(command: ReportCommand) -> Result<(), String> {
+    match command {
+        ReportCommand::Summarize(args) => summarize(args),
+    }
+}
+
+fn summarize(args: SummarizeArgs) -> Result<(), String> {
+    let mut files = vec![];
+    for path in &args.paths {
+        if path.is_dir() {
+            let entries = std::fs::read_dir(path)
+                .map_err(|err| format!("failed to read {:?}: {}", path, err))?;
+            let mut entries: Vec<PathBuf> = entries
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .filter(|path| path.is_file())
+                .collect();
+            entries.sort();
+            files.extend(entries);
+        } else {
+            files.push(path.clone());
+        }
+    }
+
+    let mut summary = Summary::default();
+    for file in &files {
+        match read_report(file) {
+            Ok(report) => summary.add(&report),
+            Err(err) => eprintln!("skipping {:?}: {}", file, err),
+        }
+    }
+    if summary.reports == 0 {
+        return Err("no valid report found".to_owned());
+    }
+
+    match args.format {
+        Format::Table => print!("{}", to_table(&summary)),
+        Format::Csv => print!("{}", to_csv(&summary)),
+        Format::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&to_json(&summary)).unwrap()
+        ),
+    }
+    Ok(())
+}
+
+fn read_report(path: &Path) -> Result<AggregateReport, String> {
+    let bytes = std::fs::read(path).map_err(|err| err.to_string())?;
+    AggregateReport::from_bytes(&bytes).map_err(|err| err.to_string())
+}
+
+fn to_table(summary: &Summary) -> String {
+    let mut out = format!("{} report(s)\n", summary.reports);
+    for (group, counts) in summary.groups() {
+        let width = counts
+            .keys()
+            .map(|key| key.len())
+            .chain([group.len()])
+            .max()
+            .unwrap_or_default();
+
+        out += &format!("\n{:<width$}", group, width = width);
+        for column in COLUMNS {
+            out += &format!(" {:>14}", column);
+        }
+        out += "\n";
+        for (key, counts) in counts {
+            out += &format!("{:<width$}", key, width = width);
+            for value in counts.values() {
+                out += &format!(" {:>14}", value);
+            }
+            out += "\n";
+        }
+    }
+    out
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+fn to_csv(summary: &Summary) -> String {
+    let mut out = format!("group,key,{}\n", COLUMNS.join(","));
+    for (group, counts) in summary.groups() {
+        for (key, counts) in counts {
+            let values: Vec<String> = counts.values().iter().map(|v| v.to_string()).collect();
+            out += &format!("{},{},{}\n", group, csv_field(key), values.join(","));
+        }
+    }
+    out
+}
+
+fn to_json(summary: &Summary) -> Value {
+    let group = |counts: &BTreeMap<String, Counts>| -> Value {
+        counts
+            .iter()
+            .map(|(key, counts)| {
+                let values: serde_json::Map<String, Value> = COLUMNS
+                    .iter()
+                    .zip(counts.values())
+                    .map(|(column, value)| (column.to_string(), json!(value)))
+                    .collect();
+                (key.clone(), Value::Object(values))
+            })
+            .collect::<serde_json::Map<String, Value>>()
+            .into()
+    };
+
+    json!({
+        "reports": summary.reports,
+        "by_source_ip": group(&summary.by_source_ip),
+        "by_reporter": group(&summary.by_reporter),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(org_name: &str, rows: &[(&str, u64, &str, &str, &str)]) -> AggregateReport {
+        let records: String = rows
+            .iter()
+            .map(|(ip, count, disposition, dkim, spf)| {
+                format!(
+                    "<record><row><source_ip>{}</source_ip><count>{}</count>\
+                     <policy_evaluated><disposition>{}</disposition><dkim>{}</dkim>\
+                     <spf>{}</spf></policy_evaluated></row>\
+                     <identifiers><header_from>example.com</header_from></identifiers></record>",
+                    ip, count, disposition, dkim, spf
+                )
+            })
+            .collect();
+        let xml = format!(
+            "<feedback><report_metadata><org_name>{}</org_name><report_id>1</report_id>\
+             <date_range><begin>0</begin><end>1</end></date_range></report_metadata>\
+             <policy_published><domain>example.com</domain><p>reject</p></policy_published>\
+             {}</feedback>",
+            org_name, records
+        );
+        AggregateReport::from_xml(&xml).unwrap()
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut summary = Summary::default();
+        summary.add(&report(
+            "a.example",
+            &[
+                ("192.0.2.1", 3, "none", "pass", "fail"),
+                ("192.0.2.2", 2, "reject", "fail", "fail"),
+            ],
+        ));
+        summary.add(&report(
+            "b,example",
+            &[("192.0.2.1", 1, "quarantine", "fail", "fail")],
+        ));
+
+        assert_eq!(summary.reports, 2);
+        assert_eq!(
+            summary.by_source_ip["192.0.2.1"],
+            Counts {
+                messages: 4,
+                pass: 3,
+                fail: 1,
+                dkim_unaligned: 1,
+                spf_unaligned: 4,
+                none: 3,
+                quarantine: 1,
+                reject: 0,
+            }
+        );
+        assert_eq!(summary.by_reporter["a.example"].messages, 5);
+        assert_eq!(summary.by_reporter["a.example"].reject, 2);
+
+        let csv = to_csv(&summary);
+        assert!(csv.starts_with("group,key,messages,pass,fail,"));
+        assert!(csv.contains("source_ip,192.0.2.1,4,3,1,1,4,3,1,0\n"));
+        assert!(csv.contains("reporter,\"b,example\",1,"));
+
+        let json = to_json(&summary);
+        assert_eq!(json["by_reporter"]["b,example"]["quarantine"], 1);
+    }
+}
@@ -0,0 +1,406 @@
+/// Caching of the DMARC policy records
+use crate::dns::{self, Answer};
+use crate::DMARCError;
+use futures::future::BoxFuture;
+use lru::LruCache;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Options of the `PolicyCache`
+#[derive(Debug, PartialEq, Clone)]
+pub struct CacheOptions {
+    /// Maximum number of cached answers, the least recently used answers are
+    /// evicted first
+    pub capacity: usize,
+    /// Minimum time an answer is cached
+    pub min_ttl: Duration,
+    /// Maximum time an answer is cached
+    pub max_ttl: Duration,
+    /// Time a positive answer is cached when the resolver doesn't provide
+    /// its TTL
+    pub default_ttl: Duration,
+    /// Maximum time a negative answer is cached, RFC2308 recommends 1 to 3
+    /// hours
+    pub max_negative_ttl: Duration,
+    /// Time a negative answer is cached when the resolver doesn't provide
+    /// its TTL. RFC2308 says they shouldn't be cached, hence the zero default.
+    pub default_negative_ttl: Duration,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        Self {
+            capacity: 10_000,
+            min_ttl: Duration::ZERO,
+            max_ttl: Duration::from_secs(24 * 60 * 60),
+            default_ttl: Duration::from_secs(5 * 60),
+            max_negative_ttl: Duration::from_secs(3 * 60 * 60),
+            default_negative_ttl: Duration::ZERO,
+        }
+    }
+}
+
+/// Counters of the `PolicyCache`
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Number of answers currently cached, including expired ones that
+    /// weren't evicted yet
+    pub entries: usize,
+}
+
+struct Entry {
+    records: Vec<String>,
+    nxdomain: bool,
+    authenticated: Option<bool>,
+    expires: Instant,
+}
+
+/// Cache of the DMARC policy records
+///
+/// It wraps a resolver and is a resolver itself, so it can be used with
+/// `load_policy_with_resolver` or `discover_policy_with_resolver`. Answers are
+/// cached for their TTL and negative answers (no record or NXDOMAIN) as
+/// specified in https://datatracker.ietf.org/doc/html/rfc2308#section-5.
+pub struct PolicyCache {
+    resolver: Arc<dyn dns::Lookup>,
+    options: CacheOptions,
+    entries: Mutex<LruCache<String, Entry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl PolicyCache {
+    pub fn new(resolver: Arc<dyn dns::Lookup>, options: CacheOptions) -> Self {
+        let capacity = NonZeroUsize::new(options.capacity).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            resolver,
+            options,
+            entries: Mutex::new(LruCache::new(capacity)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the hit and miss counters
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.entries.lock().unwrap().len(),
+        }
+    }
+
+    /// Removes all the cached answers
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    fn get(&self, name: &str) -> Option<Answer<String>> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+
+        match entries.get(name) {
+            Some(entry) if entry.expires > now => Some(
+                Answer::new(entry.records.clone())
+                    .with_ttl(Some(entry.expires - now))
+                    .with_nxdomain(entry.nxdomain)
+                    .with_authenticated(entry.authenticated),
+            ),
+            Some(_) => {
+                entries.pop(name);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, name: String, answer: &Answer<String>) {
+        let ttl = if answer.records.is_empty() {
+            answer
+                .ttl
+                .unwrap_or(self.options.default_negative_ttl)
+                .min(self.options.max_negative_ttl)
+        } else {
+            answer
+                .ttl
+                .unwrap_or(self.options.default_ttl)
+                .clamp(self.options.min_ttl, self.options.max_ttl)
+        };
+        if ttl.is_zero() {
+            return;
+        }
+
+        let entry = Entry {
+            records: answer.records.clone(),
+            nxdomain: answer.nxdomain,
+            authenticated: answer.authenticated,
+            expires: Instant::now() + ttl,
+        };
+        self.entries.lock().unwrap().put(name, entry);
+    }
+}
+
+impl dns::Lookup for PolicyCache {
+    fn lookup_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DMARCError>> {
+        Box::pin(async move { Ok(self.lookup_txt_with_ttl(name).await?.records) })
+    }
+
+    fn lookup_txt_with_ttl<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Answer<String>, DMARCError>> {
+        Box::pin(async move {
+            let key = name.to_lowercase();
+            if let Some(answer) = self.get(&key) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(answer);
+            }
+            self.misses.fetch_add(1, Ordering::Relaxed);
+
+            // Errors aren't cached
+            let answer = self.resolver.lookup_txt_with_ttl(name).await?;
+            self.insert(key, &answer);
+            Ok(answer)
+        })
+    }
+
+    fn domain_exists<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<bool, DMARCError>> {
+        self.resolver.domain_exists(name)
+    }
+
+    fn lookup_a<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<Ipv4Addr>, DMARCError>> {
+        self.resolver.lookup_a(name)
+    }
+
+    fn lookup_aaaa<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<Ipv6Addr>, DMARCError>> {
+        self.resolver.lookup_aaaa(name)
+    }
+
+    fn lookup_mx<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DMARCError>> {
+        self.resolver.lookup_mx(name)
+    }
+
+    fn lookup_ptr(&self, ip: IpAddr) -> BoxFuture<'_, Result<Vec<String>, DMARCError>> {
+        self.resolver.lookup_ptr(ip)
+    }
+
+    fn lookup_a_with_ttl<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Answer<Ipv4Addr>, DMARCError>> {
+        self.resolver.lookup_a_with_ttl(name)
+    }
+
+    fn lookup_aaaa_with_ttl<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Answer<Ipv6Addr>, DMARCError>> {
+        self.resolver.lookup_aaaa_with_ttl(name)
+    }
+
+    fn lookup_mx_with_ttl<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Answer<String>, DMARCError>> {
+        self.resolver.lookup_mx_with_ttl(name)
+    }
+
+    fn lookup_ptr_with_ttl(&self, ip: IpAddr) -> BoxFuture<'_, Result<Answer<String>, DMARCError>> {
+        self.resolver.lookup_ptr_with_ttl(ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::testing::TestResolver;
+    use crate::dns::Lookup;
+    use crate::load_policy_with_resolver;
+
+    fn logger() -> slog::Logger {
+        slog::Logger::root(slog::Discard, slog::o!())
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let resolver = TestResolver::default()
+            .txt("_dmarc.example.com", "v=DMARC1; p=none; pct=13;")
+            .ttl(Duration::from_secs(60))
+            .authenticated();
+        let queries = resolver.queries();
+        let cache: Arc<dyn dns::Lookup> =
+            Arc::new(PolicyCache::new(resolver.build(), CacheOptions::default()));
+
+        for _ in 0..3 {
+            let policy = load_policy_with_resolver(Arc::clone(&cache), &logger(), "a.example.com")
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(policy.pct, 13);
+        }
+        // The negative answer for _dmarc.a.example.com is cached as well
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cache_stats() {
+        let resolver = TestResolver::default()
+            .txt("_dmarc.example.com", "v=DMARC1; p=none;")
+            .ttl(Duration::from_secs(60))
+            .authenticated();
+        let cache = PolicyCache::new(resolver.build(), CacheOptions::default());
+
+        cache.lookup_txt("_dmarc.example.com").await.unwrap();
+        cache.lookup_txt("_dmarc.EXAMPLE.com").await.unwrap();
+        let answer = cache
+            .lookup_txt_with_ttl("_dmarc.example.com")
+            .await
+            .unwrap();
+        assert_eq!(answer.records, vec!["v=DMARC1; p=none;"]);
+        assert!(answer.ttl.unwrap() <= Duration::from_secs(60));
+
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 2,
+                misses: 1,
+                entries: 1
+            }
+        );
+
+        cache.clear();
+        assert_eq!(cache.stats().entries, 0);
+    }
+
+    #[tokio::test]
+    async fn test_cache_ttl() {
+        // Answers with a zero TTL aren't cached
+        let resolver = TestResolver::default()
+            .txt("_dmarc.example.com", "v=DMARC1; p=none;")
+            .ttl(Duration::ZERO);
+        let queries = resolver.queries();
+        let cache = PolicyCache::new(resolver.build(), CacheOptions::default());
+        cache.lookup_txt("_dmarc.example.com").await.unwrap();
+        cache.lookup_txt("_dmarc.example.com").await.unwrap();
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+
+        // Unless a minimum TTL is configured
+        let resolver = TestResolver::default()
+            .txt("_dmarc.example.com", "v=DMARC1; p=none;")
+            .ttl(Duration::ZERO);
+        let queries = resolver.queries();
+        let options = CacheOptions {
+            min_ttl: Duration::from_secs(10),
+            ..CacheOptions::default()
+        };
+        let cache = PolicyCache::new(resolver.build(), options);
+        cache.lookup_txt("_dmarc.example.com").await.unwrap();
+        cache.lookup_txt("_dmarc.example.com").await.unwrap();
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cache_negative() {
+        // Without TTL, negative answers aren't cached
+        let resolver = TestResolver::default();
+        let queries = resolver.queries();
+        let cache = PolicyCache::new(resolver.build(), CacheOptions::default());
+        cache.lookup_txt("_dmarc.example.com").await.unwrap();
+        cache.lookup_txt("_dmarc.example.com").await.unwrap();
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+
+        // Positive answers are cached with the default TTL
+        let resolver = TestResolver::default().txt("_dmarc.example.com", "v=DMARC1; p=none;");
+        let queries = resolver.queries();
+        let cache = PolicyCache::new(resolver.build(), CacheOptions::default());
+        cache.lookup_txt("_dmarc.example.com").await.unwrap();
+        cache.lookup_txt("_dmarc.example.com").await.unwrap();
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cache_answer_flags() {
+        let resolver = TestResolver::default()
+            .txt("_dmarc.example.com", "v=DMARC1; p=none;")
+            .nxdomain("_dmarc.a.example.com")
+            .ttl(Duration::from_secs(60))
+            .authenticated();
+        let queries = resolver.queries();
+        let cache = PolicyCache::new(resolver.build(), CacheOptions::default());
+
+        for _ in 0..2 {
+            let answer = cache
+                .lookup_txt_with_ttl("_dmarc.example.com")
+                .await
+                .unwrap();
+            assert_eq!(answer.authenticated, Some(true));
+            assert!(!answer.nxdomain);
+
+            let answer = cache
+                .lookup_txt_with_ttl("_dmarc.a.example.com")
+                .await
+                .unwrap();
+            assert!(answer.records.is_empty());
+            assert!(answer.nxdomain);
+        }
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cache_eviction() {
+        let resolver = TestResolver::default()
+            .txt("_dmarc.a.com", "v=DMARC1; p=none;")
+            .txt("_dmarc.b.com", "v=DMARC1; p=none;")
+            .ttl(Duration::from_secs(60))
+            .authenticated();
+        let queries = resolver.queries();
+        let options = CacheOptions {
+            capacity: 1,
+            ..CacheOptions::default()
+        };
+        let cache = PolicyCache::new(resolver.build(), options);
+
+        cache.lookup_txt("_dmarc.a.com").await.unwrap();
+        cache.lookup_txt("_dmarc.b.com").await.unwrap();
+        cache.lookup_txt("_dmarc.a.com").await.unwrap();
+        assert_eq!(queries.load(Ordering::SeqCst), 3);
+        assert_eq!(cache.stats().entries, 1);
+    }
+
+    #[tokio::test]
+    async fn test_cache_forwards_lookups() {
+        let resolver = TestResolver::default()
+            .ip("example.com", "192.0.2.1")
+            .ip("example.com", "2001:db8::1")
+            .mx("example.com", "mx.example.com")
+            .ptr("192.0.2.1", "example.com");
+        let cache = PolicyCache::new(resolver.build(), CacheOptions::default());
+
+        assert_eq!(
+            cache.lookup_a("example.com").await.unwrap(),
+            vec![Ipv4Addr::new(192, 0, 2, 1)]
+        );
+        assert_eq!(cache.lookup_aaaa("example.com").await.unwrap().len(), 1);
+        assert_eq!(
+            cache.lookup_mx("example.com").await.unwrap(),
+            vec!["mx.example.com"]
+        );
+        assert_eq!(
+            cache
+                .lookup_ptr("192.0.2.1".parse().unwrap())
+                .await
+                .unwrap(),
+            vec!["example.com"]
+        );
+        // Only the TXT answers are cached
+        assert_eq!(cache.stats().entries, 0);
+    }
+}
@@ -0,0 +1,237 @@
+/// Coalescing of concurrent identical DNS queries
+use crate::dns::{self, Answer};
+use crate::DMARCError;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+type InFlight = Shared<BoxFuture<'static, Result<Answer<String>, DMARCError>>>;
+
+/// Resolver sharing a single in-flight query between concurrent lookups of
+/// the same name (single-flight)
+///
+/// It can be used on its own or behind a `PolicyCache`, in which case the
+/// concurrent cache misses share the same query.
+pub struct CoalescingResolver {
+    resolver: Arc<dyn dns::Lookup>,
+    in_flight: Arc<Mutex<HashMap<String, InFlight>>>,
+    coalesced: AtomicU64,
+}
+
+impl CoalescingResolver {
+    pub fn new(resolver: Arc<dyn dns::Lookup>) -> Self {
+        Self {
+            resolver,
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            coalesced: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the number of lookups that joined a query already in flight
+    pub fn coalesced(&self) -> u64 {
+        self.coalesced.load(Ordering::Relaxed)
+    }
+}
+
+impl dns::Lookup for CoalescingResolver {
+    fn lookup_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DMARCError>> {
+        Box::pin(async move { Ok(self.lookup_txt_with_ttl(name).await?.records) })
+    }
+
+    fn lookup_txt_with_ttl<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Answer<String>, DMARCError>> {
+        let key = name.to_lowercase();
+
+        let query = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            if let Some(query) = in_flight.get(&key) {
+                self.coalesced.fetch_add(1, Ordering::Relaxed);
+                query.clone()
+            } else {
+                let resolver = Arc::clone(&self.resolver);
+                let in_flight_ref = Arc::clone(&self.in_flight);
+                let name = name.to_owned();
+                let query_key = key.clone();
+
+                let query = async move {
+                    let res = resolver.lookup_txt_with_ttl(&name).await;
+                    // The next lookups will issue a new query
+                    in_flight_ref.lock().unwrap().remove(&query_key);
+                    res
+                }
+                .boxed()
+                .shared();
+                in_flight.insert(key, query.clone());
+                query
+            }
+        };
+
+        Box::pin(query)
+    }
+
+    fn domain_exists<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<bool, DMARCError>> {
+        self.resolver.domain_exists(name)
+    }
+
+    fn lookup_a<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<Ipv4Addr>, DMARCError>> {
+        self.resolver.lookup_a(name)
+    }
+
+    fn lookup_aaaa<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<Ipv6Addr>, DMARCError>> {
+        self.resolver.lookup_aaaa(name)
+    }
+
+    fn lookup_mx<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DMARCError>> {
+        self.resolver.lookup_mx(name)
+    }
+
+    fn lookup_ptr(&self, ip: IpAddr) -> BoxFuture<'_, Result<Vec<String>, DMARCError>> {
+        self.resolver.lookup_ptr(ip)
+    }
+
+    fn lookup_a_with_ttl<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Answer<Ipv4Addr>, DMARCError>> {
+        self.resolver.lookup_a_with_ttl(name)
+    }
+
+    fn lookup_aaaa_with_ttl<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Answer<Ipv6Addr>, DMARCError>> {
+        self.resolver.lookup_aaaa_with_ttl(name)
+    }
+
+    fn lookup_mx_with_ttl<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Answer<String>, DMARCError>> {
+        self.resolver.lookup_mx_with_ttl(name)
+    }
+
+    fn lookup_ptr_with_ttl(&self, ip: IpAddr) -> BoxFuture<'_, Result<Answer<String>, DMARCError>> {
+        self.resolver.lookup_ptr_with_ttl(ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::testing::TestResolver;
+    use crate::dns::Lookup;
+    use crate::{CacheOptions, PolicyCache};
+    use futures::channel::oneshot;
+    use std::time::Duration;
+
+    /// Resolver that waits to be released before answering, so that
+    /// concurrent lookups overlap
+    struct SlowResolver {
+        inner: Arc<dyn dns::Lookup>,
+        release: Shared<oneshot::Receiver<()>>,
+    }
+
+    impl dns::Lookup for SlowResolver {
+        fn lookup_txt<'a>(
+            &'a self,
+            name: &'a str,
+        ) -> BoxFuture<'a, Result<Vec<String>, DMARCError>> {
+            Box::pin(async move { Ok(self.lookup_txt_with_ttl(name).await?.records) })
+        }
+
+        fn lookup_txt_with_ttl<'a>(
+            &'a self,
+            name: &'a str,
+        ) -> BoxFuture<'a, Result<Answer<String>, DMARCError>> {
+            Box::pin(async move {
+                self.release.clone().await.unwrap();
+                self.inner.lookup_txt_with_ttl(name).await
+            })
+        }
+    }
+
+    fn slow(resolver: TestResolver) -> (Arc<dyn dns::Lookup>, oneshot::Sender<()>) {
+        let (tx, rx) = oneshot::channel();
+        let resolver = Arc::new(SlowResolver {
+            inner: resolver.build(),
+            release: rx.shared(),
+        });
+        (resolver, tx)
+    }
+
+    /// Runs the lookups and then answers them
+    async fn run<F: std::future::Future>(lookups: F, release: oneshot::Sender<()>) -> F::Output {
+        let release = async move {
+            tokio::task::yield_now().await;
+            release.send(()).unwrap();
+        };
+        futures::join!(lookups, release).0
+    }
+
+    #[tokio::test]
+    async fn test_coalesce() {
+        let resolver = TestResolver::default().txt("_dmarc.example.com", "v=DMARC1; p=none;");
+        let queries = resolver.queries();
+        let (resolver, release) = slow(resolver);
+        let coalescing = CoalescingResolver::new(resolver);
+
+        let lookups = (0..10).map(|_| coalescing.lookup_txt("_dmarc.example.com"));
+        let results = run(futures::future::join_all(lookups), release).await;
+        for res in results {
+            assert_eq!(res.unwrap(), vec!["v=DMARC1; p=none;"]);
+        }
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+        assert_eq!(coalescing.coalesced(), 9);
+
+        // Once the query completed a new one is issued
+        coalescing.lookup_txt("_dmarc.example.com").await.unwrap();
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_different_names() {
+        let resolver = TestResolver::default();
+        let queries = resolver.queries();
+        let (resolver, release) = slow(resolver);
+        let coalescing = CoalescingResolver::new(resolver);
+
+        let lookups = async {
+            futures::join!(
+                coalescing.lookup_txt("_dmarc.a.com"),
+                coalescing.lookup_txt("_dmarc.b.com")
+            )
+        };
+        let (a, b) = run(lookups, release).await;
+        assert!(a.unwrap().is_empty());
+        assert!(b.unwrap().is_empty());
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_with_cache() {
+        let resolver = TestResolver::default()
+            .txt("_dmarc.example.com", "v=DMARC1; p=none;")
+            .ttl(Duration::from_secs(60));
+        let queries = resolver.queries();
+        let (resolver, release) = slow(resolver);
+        let cache = PolicyCache::new(
+            Arc::new(CoalescingResolver::new(resolver)),
+            CacheOptions::default(),
+        );
+
+        let lookups = (0..10).map(|_| cache.lookup_txt("_dmarc.example.com"));
+        run(futures::future::join_all(lookups), release).await;
+        cache.lookup_txt("_dmarc.example.com").await.unwrap();
+
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.stats().misses, 10);
+        assert_eq!(cache.stats().hits, 1);
+    }
+}
@@ -0,0 +1,585 @@
+/// Discovery of the DMARC policy and the Organizational Domain
+use crate::{
+    dns, parse_policy, parser, DMARCError, OrgDomainResolver, Policy, PublicSuffixDomain,
+    DNS_SUBDOMAIN,
+};
+use slog::warn;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Maximum number of labels queried by the DNS tree walk, as specified in
+/// https://datatracker.ietf.org/doc/html/draft-ietf-dmarc-dmarcbis#section-4.10
+const TREE_WALK_MAX_LABELS: usize = 8;
+
+/// How the policy and Organizational Domain are discovered
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum DiscoveryMode {
+    /// Look at the exact domain, then at the Organizational Domain computed
+    /// from the Public Suffix List as specified in RFC7489
+    #[default]
+    PublicSuffixList,
+    /// DNS tree walk as specified in DMARCbis, honoring the `psd` tag
+    TreeWalk,
+}
+
+/// Options of the policy discovery
+#[derive(Debug, Clone, Default)]
+pub struct DiscoveryOptions {
+    pub mode: DiscoveryMode,
+    /// Resolver of Organizational Domains used by
+    /// `DiscoveryMode::PublicSuffixList`
+    pub org_domain_resolver: Arc<OrgDomainResolver>,
+    /// Public Suffix Domains participating in PSD DMARC
+    /// (https://datatracker.ietf.org/doc/html/rfc9091), queried as a third
+    /// step when using `DiscoveryMode::PublicSuffixList`
+    pub psd_domains: Vec<String>,
+    /// Local policies used instead of the published ones, by domain. A policy
+    /// set for an Organizational Domain also applies to its subdomains.
+    pub overrides: HashMap<String, Policy>,
+    /// When multiple DMARC records are published at a domain, use the first
+    /// valid one instead of stopping the discovery as required by RFC7489
+    pub lenient_multiple_records: bool,
+}
+
+/// Outcome of the policy discovery
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Discovery {
+    /// DMARC policy applying to the RFC5322.From domain, if any
+    pub policy: Option<Policy>,
+    /// Domain where the policy record was found
+    pub policy_domain: Option<String>,
+    /// Organizational Domain of the RFC5322.From domain
+    pub organizational_domain: String,
+    /// Whether the policy was published by a Public Suffix Domain
+    pub from_psd: bool,
+    /// Whether the policy is a local override
+    pub overridden: bool,
+    /// Misconfigurations found during the discovery
+    pub diagnostics: Vec<DiscoveryDiagnostic>,
+}
+
+/// Misconfiguration of the published DMARC records
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(tag = "kind", rename_all = "snake_case"))]
+pub enum DiscoveryDiagnostic {
+    /// More than one DMARC record is published at the domain
+    MultipleRecords { domain: String, count: usize },
+    /// The DMARC record published at the domain is invalid
+    InvalidRecord {
+        domain: String,
+        #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_error"))]
+        error: DMARCError,
+    },
+}
+
+/// Errors are serialized as their message
+#[cfg(feature = "serde")]
+fn serialize_error<S: serde::Serializer>(
+    error: &DMARCError,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(error)
+}
+
+/// Discover the DMARC policy for the domain
+///
+/// Unlike `load_policy_with_resolver` it also returns where the policy was
+/// found and the Organizational Domain.
+pub async fn discover_policy_with_resolver<'a>(
+    resolver: Arc<dyn dns::Lookup>,
+    logger: &'a slog::Logger,
+    from_domain: &'a str,
+    options: &'a DiscoveryOptions,
+) -> Result<Discovery, DMARCError> {
+    if let Some(discovery) = override_discovery(from_domain, options) {
+        return Ok(discovery);
+    }
+
+    let mut lookup = PolicyLookup {
+        resolver,
+        logger,
+        lenient_multiple_records: options.lenient_multiple_records,
+        diagnostics: vec![],
+    };
+    let mut discovery = match options.mode {
+        DiscoveryMode::PublicSuffixList => psl_discovery(&mut lookup, from_domain, options).await?,
+        DiscoveryMode::TreeWalk => tree_walk_discovery(&mut lookup, from_domain).await?,
+    };
+    discovery.diagnostics = lookup.diagnostics;
+    Ok(discovery)
+}
+
+impl Discovery {
+    fn not_found(organizational_domain: String) -> Self {
+        Self {
+            policy: None,
+            policy_domain: None,
+            organizational_domain,
+            from_psd: false,
+            overridden: false,
+            diagnostics: vec![],
+        }
+    }
+}
+
+/// Returns the local policy overriding the published one, if any
+fn override_discovery(from_domain: &str, options: &DiscoveryOptions) -> Option<Discovery> {
+    if options.overrides.is_empty() {
+        return None;
+    }
+
+    let from_domain = from_domain.to_lowercase();
+    let organizational_domain = options
+        .org_domain_resolver
+        .organizational_domain(&from_domain)
+        .unwrap_or_else(|| from_domain.clone());
+
+    let (domain, policy) = [&from_domain, &organizational_domain]
+        .into_iter()
+        .find_map(|domain| Some((domain, options.overrides.get(domain)?)))?;
+
+    Some(Discovery {
+        policy: Some(policy.clone()),
+        policy_domain: Some(domain.clone()),
+        overridden: true,
+        ..Discovery::not_found(organizational_domain)
+    })
+}
+
+// https://datatracker.ietf.org/doc/html/rfc7489#section-6.6.3
+async fn psl_discovery(
+    lookup: &mut PolicyLookup<'_>,
+    from_domain: &str,
+    options: &DiscoveryOptions,
+) -> Result<Discovery, DMARCError> {
+    let root = options
+        .org_domain_resolver
+        .organizational_domain(from_domain);
+    let mut discovery =
+        Discovery::not_found(root.clone().unwrap_or_else(|| from_domain.to_owned()));
+
+    // Search DMARC policy at the current domain
+    match lookup.lookup(from_domain, false).await? {
+        PublishedPolicy::Found(policy) => {
+            discovery.policy = Some(policy);
+            discovery.policy_domain = Some(from_domain.to_owned());
+            return Ok(discovery);
+        }
+        PublishedPolicy::Multiple => return Ok(discovery),
+        PublishedPolicy::NotFound => {}
+    }
+
+    // No policy was found, if the domain was a subdomain try at the root domain
+    if let Some(root) = root {
+        match lookup.lookup(&root, true).await? {
+            PublishedPolicy::Found(policy) => {
+                discovery.policy = Some(policy);
+                discovery.policy_domain = Some(root);
+                return Ok(discovery);
+            }
+            PublishedPolicy::Multiple => return Ok(discovery),
+            PublishedPolicy::NotFound => {}
+        }
+    }
+
+    // Still no policy, try at the Public Suffix Domain if it participates in
+    // PSD DMARC. The Organizational Domain is a subdomain of the PSD so the
+    // `sp` and `np` tags apply.
+    // https://datatracker.ietf.org/doc/html/rfc9091#section-4
+    if let Some(psd) = find_psd_domain(&discovery.organizational_domain, &options.psd_domains) {
+        if let PublishedPolicy::Found(policy) = lookup.lookup(psd, true).await? {
+            discovery.policy = Some(policy);
+            discovery.policy_domain = Some(psd.to_owned());
+            discovery.from_psd = true;
+            return Ok(discovery);
+        }
+    }
+
+    // Finally, if no policy was found return nothing
+    Ok(discovery)
+}
+
+// https://datatracker.ietf.org/doc/html/draft-ietf-dmarc-dmarcbis#section-4.10
+async fn tree_walk_discovery(
+    lookup: &mut PolicyLookup<'_>,
+    from_domain: &str,
+) -> Result<Discovery, DMARCError> {
+    let mut discovery = Discovery::not_found(from_domain.to_owned());
+    // Domain with the fewest labels where a record was found
+    let mut last_found: Option<String> = None;
+
+    let candidates = tree_walk_candidates(from_domain);
+    for (i, domain) in candidates.iter().enumerate() {
+        let is_root = domain != from_domain;
+        let policy = match lookup.lookup(domain, is_root).await? {
+            PublishedPolicy::Found(v) => v,
+            PublishedPolicy::NotFound => continue,
+            // The walk stops, without policy if none was found yet
+            PublishedPolicy::Multiple => break,
+        };
+        let psd = policy.psd.clone();
+
+        // The first record found is the policy applying to the domain
+        if discovery.policy.is_none() {
+            discovery.from_psd = i > 0 && psd == PublicSuffixDomain::Yes;
+            discovery.policy = Some(policy);
+            discovery.policy_domain = Some(domain.clone());
+        }
+
+        // https://datatracker.ietf.org/doc/html/draft-ietf-dmarc-dmarcbis#section-4.10.2
+        match psd {
+            PublicSuffixDomain::No => {
+                discovery.organizational_domain = domain.clone();
+                return Ok(discovery);
+            }
+            PublicSuffixDomain::Yes if i > 0 => {
+                discovery.organizational_domain = one_label_below(from_domain, domain);
+                return Ok(discovery);
+            }
+            _ => last_found = Some(domain.clone()),
+        }
+    }
+
+    if let Some(domain) = last_found {
+        discovery.organizational_domain = domain;
+    }
+    Ok(discovery)
+}
+
+/// Returns the longest configured Public Suffix Domain that is a parent of the
+/// Organizational Domain
+fn find_psd_domain<'a>(organizational_domain: &str, psd_domains: &'a [String]) -> Option<&'a str> {
+    psd_domains
+        .iter()
+        .map(|psd| psd.trim_end_matches('.'))
+        .filter(|psd| {
+            organizational_domain
+                .strip_suffix(psd)
+                .map(|prefix| prefix.ends_with('.'))
+                .unwrap_or(false)
+        })
+        .max_by_key(|psd| psd.len())
+}
+
+/// Returns the domains queried by the DNS tree walk, starting with the
+/// domain itself. Domains with more than 8 labels are shortened to 7 labels
+/// after the first query.
+fn tree_walk_candidates(from_domain: &str) -> Vec<String> {
+    let labels: Vec<&str> = from_domain.split('.').collect();
+    let start = labels.len().saturating_sub(TREE_WALK_MAX_LABELS - 1).max(1);
+
+    let mut candidates = vec![from_domain.to_owned()];
+    for i in start..labels.len() {
+        candidates.push(labels[i..].join("."));
+    }
+    candidates
+}
+
+/// Returns the ancestor of `from_domain` that is one label longer than
+/// `domain`
+fn one_label_below(from_domain: &str, domain: &str) -> String {
+    let labels: Vec<&str> = from_domain.split('.').collect();
+    let count = domain.split('.').count() + 1;
+    labels[labels.len() - count.min(labels.len())..].join(".")
+}
+
+/// Outcome of the lookup of the DMARC policy published at a domain
+enum PublishedPolicy {
+    Found(Policy),
+    /// No valid record, the discovery continues
+    NotFound,
+    /// More than one record, the discovery terminates
+    Multiple,
+}
+
+/// Loads the DMARC policies published at domains and collects diagnostics
+struct PolicyLookup<'a> {
+    resolver: Arc<dyn dns::Lookup>,
+    logger: &'a slog::Logger,
+    lenient_multiple_records: bool,
+    diagnostics: Vec<DiscoveryDiagnostic>,
+}
+
+impl PolicyLookup<'_> {
+    /// Load the DMARC policy published at the domain
+    ///
+    /// As specified in https://datatracker.ietf.org/doc/html/rfc7489#section-6.6.3
+    /// only the records starting with `v=DMARC1` are considered and, unless
+    /// in lenient mode, if more than one remains the discovery terminates.
+    async fn lookup(&mut self, domain: &str, is_root: bool) -> Result<PublishedPolicy, DMARCError> {
+        let name = format!("{}.{}", DNS_SUBDOMAIN, domain);
+        let records: Vec<String> = self
+            .resolver
+            .lookup_txt(&name)
+            .await?
+            .into_iter()
+            .filter(|record| parser::is_dmarc_record(record))
+            .collect();
+
+        if records.len() > 1 {
+            warn!(
+                self.logger,
+                "found {} DMARC records at {}",
+                records.len(),
+                domain
+            );
+            self.diagnostics.push(DiscoveryDiagnostic::MultipleRecords {
+                domain: domain.to_owned(),
+                count: records.len(),
+            });
+            if !self.lenient_multiple_records {
+                return Ok(PublishedPolicy::Multiple);
+            }
+        }
+
+        for record in records {
+            match parse_policy(&record, is_root) {
+                Ok(policy) => return Ok(PublishedPolicy::Found(policy)),
+                Err(err) => {
+                    warn!(self.logger, "DMARC policy parse error: {}", err);
+                    self.diagnostics.push(DiscoveryDiagnostic::InvalidRecord {
+                        domain: domain.to_owned(),
+                        error: err,
+                    });
+                }
+            }
+        }
+        Ok(PublishedPolicy::NotFound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::testing::TestResolver;
+
+    async fn discover(resolver: TestResolver, from_domain: &str, mode: DiscoveryMode) -> Discovery {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let options = DiscoveryOptions {
+            mode,
+            ..DiscoveryOptions::default()
+        };
+        discover_policy_with_resolver(resolver.build(), &logger, from_domain, &options)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_multiple_records() {
+        let resolver = || {
+            TestResolver::default()
+                .txt("_dmarc.sub.example.com", "v=DMARC1; p=reject;")
+                .txt("_dmarc.sub.example.com", "v=DMARC1; p=quarantine;")
+                .txt("_dmarc.example.com", "v=DMARC1; p=none; sp=none;")
+        };
+
+        // The discovery stops without policy, the root isn't queried
+        let discovery = discover(
+            resolver(),
+            "sub.example.com",
+            DiscoveryMode::PublicSuffixList,
+        )
+        .await;
+        assert_eq!(discovery.policy, None);
+        assert_eq!(discovery.policy_domain, None);
+        assert_eq!(
+            discovery.diagnostics,
+            vec![DiscoveryDiagnostic::MultipleRecords {
+                domain: "sub.example.com".to_owned(),
+                count: 2
+            }]
+        );
+
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let options = DiscoveryOptions {
+            lenient_multiple_records: true,
+            ..DiscoveryOptions::default()
+        };
+        let discovery =
+            discover_policy_with_resolver(resolver().build(), &logger, "sub.example.com", &options)
+                .await
+                .unwrap();
+        assert_eq!(discovery.policy_domain.as_deref(), Some("sub.example.com"));
+        assert_eq!(
+            discovery.policy.unwrap().action,
+            crate::ReceiverAction::Reject
+        );
+        assert_eq!(discovery.diagnostics.len(), 1);
+
+        let discovery = discover(resolver(), "sub.example.com", DiscoveryMode::TreeWalk).await;
+        assert_eq!(discovery.policy, None);
+        assert_eq!(discovery.policy_domain, None);
+    }
+
+    #[tokio::test]
+    async fn test_non_dmarc_records() {
+        let resolver = TestResolver::default()
+            .txt("_dmarc.example.com", "v=spf1 -all")
+            .txt("_dmarc.example.com", "v=DMARC10; p=reject;")
+            .txt("_dmarc.example.com", "p=reject; v=DMARC1")
+            .txt("_dmarc.example.com", "v = DMARC1 ; p=quarantine;");
+        let discovery = discover(resolver, "example.com", DiscoveryMode::PublicSuffixList).await;
+        assert_eq!(
+            discovery.policy.unwrap().action,
+            crate::ReceiverAction::Quarantine
+        );
+        assert!(discovery.diagnostics.is_empty());
+
+        let resolver = TestResolver::default().txt("_dmarc.example.com", "v=DMARC1; p=hein;");
+        let discovery = discover(resolver, "example.com", DiscoveryMode::PublicSuffixList).await;
+        assert!(discovery.policy.is_none());
+        assert!(matches!(
+            discovery.diagnostics[0],
+            DiscoveryDiagnostic::InvalidRecord { .. }
+        ));
+    }
+
+    #[test]
+    fn test_tree_walk_candidates() {
+        assert_eq!(
+            tree_walk_candidates("a.b.example.com"),
+            vec!["a.b.example.com", "b.example.com", "example.com", "com"]
+        );
+        assert_eq!(
+            tree_walk_candidates("a.b.c.d.e.f.g.h.i.j"),
+            vec![
+                "a.b.c.d.e.f.g.h.i.j",
+                "d.e.f.g.h.i.j",
+                "e.f.g.h.i.j",
+                "f.g.h.i.j",
+                "g.h.i.j",
+                "h.i.j",
+                "i.j",
+                "j"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_psl_discovery() {
+        let resolver = TestResolver::default().txt("_dmarc.example.com", "v=DMARC1; p=none;");
+        let discovery =
+            discover(resolver, "a.b.example.com", DiscoveryMode::PublicSuffixList).await;
+        assert!(discovery.policy.is_some());
+        assert_eq!(discovery.policy_domain.as_deref(), Some("example.com"));
+        assert_eq!(discovery.organizational_domain, "example.com");
+    }
+
+    #[tokio::test]
+    async fn test_psd_discovery() {
+        let resolver = TestResolver::default()
+            .txt(
+                "_dmarc.gov.uk",
+                "v=DMARC1; p=none; sp=quarantine; np=reject;",
+            )
+            .build();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+
+        // PSD DMARC isn't used unless the PSD is configured
+        let options = DiscoveryOptions::default();
+        let discovery = discover_policy_with_resolver(
+            Arc::clone(&resolver),
+            &logger,
+            "a.agency.gov.uk",
+            &options,
+        )
+        .await
+        .unwrap();
+        assert!(discovery.policy.is_none());
+
+        let options = DiscoveryOptions {
+            psd_domains: vec!["uk".to_owned(), "gov.uk".to_owned()],
+            ..DiscoveryOptions::default()
+        };
+        let discovery =
+            discover_policy_with_resolver(resolver, &logger, "a.agency.gov.uk", &options)
+                .await
+                .unwrap();
+        assert!(discovery.from_psd);
+        assert_eq!(discovery.policy_domain.as_deref(), Some("gov.uk"));
+        assert_eq!(discovery.organizational_domain, "agency.gov.uk");
+        let policy = discovery.policy.unwrap();
+        assert_eq!(policy.action, crate::ReceiverAction::Quarantine);
+        assert_eq!(policy.np, Some(crate::ReceiverAction::Reject));
+    }
+
+    #[test]
+    fn test_find_psd_domain() {
+        let psd_domains = vec!["gov.example".to_owned(), "example".to_owned()];
+        assert_eq!(
+            find_psd_domain("agency.gov.example", &psd_domains),
+            Some("gov.example")
+        );
+        assert_eq!(
+            find_psd_domain("gov.example", &psd_domains),
+            Some("example")
+        );
+        assert_eq!(
+            find_psd_domain("notgov.example", &psd_domains),
+            Some("example")
+        );
+        assert_eq!(find_psd_domain("example.com", &psd_domains), None);
+    }
+
+    #[tokio::test]
+    async fn test_tree_walk_discovery() {
+        let resolver = TestResolver::default()
+            .txt("_dmarc.b.example.com", "v=DMARC1; p=reject; sp=quarantine;")
+            .txt("_dmarc.example.com", "v=DMARC1; p=none;");
+        let discovery = discover(resolver, "a.b.example.com", DiscoveryMode::TreeWalk).await;
+        assert_eq!(
+            discovery.policy.unwrap().action,
+            crate::ReceiverAction::Quarantine
+        );
+        assert_eq!(discovery.policy_domain.as_deref(), Some("b.example.com"));
+        assert_eq!(discovery.organizational_domain, "example.com");
+    }
+
+    #[tokio::test]
+    async fn test_tree_walk_discovery_psd() {
+        // psd=n stops the walk
+        let resolver = TestResolver::default()
+            .txt("_dmarc.b.example.com", "v=DMARC1; p=reject; psd=n;")
+            .txt("_dmarc.example.com", "v=DMARC1; p=none;");
+        let discovery = discover(resolver, "a.b.example.com", DiscoveryMode::TreeWalk).await;
+        assert_eq!(discovery.organizational_domain, "b.example.com");
+
+        // psd=y makes the domain one label below the Organizational Domain
+        let resolver =
+            TestResolver::default().txt("_dmarc.example.com", "v=DMARC1; p=reject; psd=y;");
+        let discovery = discover(resolver, "a.b.example.com", DiscoveryMode::TreeWalk).await;
+        assert_eq!(discovery.policy_domain.as_deref(), Some("example.com"));
+        assert_eq!(discovery.organizational_domain, "b.example.com");
+
+        // No record found, the domain itself is the Organizational Domain
+        let discovery = discover(
+            TestResolver::default(),
+            "a.example.com",
+            DiscoveryMode::TreeWalk,
+        )
+        .await;
+        assert!(discovery.policy.is_none());
+        assert_eq!(discovery.organizational_domain, "a.example.com");
+    }
+
+    #[tokio::test]
+    async fn test_tree_walk_discovery_multiple_records() {
+        // The policy of the From domain still applies, the Organizational
+        // Domain is the last domain with a record before the walk stopped
+        let resolver = TestResolver::default()
+            .txt("_dmarc.a.b.example.com", "v=DMARC1; p=reject;")
+            .txt("_dmarc.b.example.com", "v=DMARC1; p=none;")
+            .txt("_dmarc.example.com", "v=DMARC1; p=none;")
+            .txt("_dmarc.example.com", "v=DMARC1; p=quarantine;");
+        let discovery = discover(resolver, "a.b.example.com", DiscoveryMode::TreeWalk).await;
+        assert_eq!(
+            discovery.policy.unwrap().action,
+            crate::ReceiverAction::Reject
+        );
+        assert_eq!(discovery.policy_domain.as_deref(), Some("a.b.example.com"));
+        assert_eq!(discovery.organizational_domain, "b.example.com");
+        assert_eq!(discovery.diagnostics.len(), 1);
+    }
+}
@@ -1,50 +1,399 @@
 /// Module to abstract DNS operations
 use crate::DMARCError;
 use futures::future::BoxFuture;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::Arc;
-use trust_dns_resolver::error::ResolveErrorKind;
-use trust_dns_resolver::TokioAsyncResolver;
+use std::time::{Duration, Instant};
+use trust_dns_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+use trust_dns_resolver::name_server::{
+    GenericConnector, GenericNameServerPool, NameServerPool, TokioRuntimeProvider,
+};
+use trust_dns_resolver::proto::op::{Edns, Message, MessageType, OpCode, Query, ResponseCode};
+use trust_dns_resolver::proto::rr::{RData, RecordType};
+use trust_dns_resolver::proto::xfer::{DnsHandle, DnsRequest, DnsRequestOptions, FirstAnswer};
+use trust_dns_resolver::{Name, TokioAsyncResolver};
+
+/// Answer to a DNS query
+///
+/// Fields may be added, so answers are built with `Answer::new` and the
+/// `with_*` methods.
+#[derive(Debug, PartialEq, Clone)]
+#[non_exhaustive]
+pub struct Answer<T> {
+    pub records: Vec<T>,
+    /// How long the answer can be cached, `None` if unknown. For negative
+    /// answers it's derived from the SOA record as specified in
+    /// https://datatracker.ietf.org/doc/html/rfc2308#section-5
+    pub ttl: Option<Duration>,
+    /// The domain doesn't exist (NXDOMAIN). Without records and with this
+    /// flag unset, the domain exists but has no record of this type (NODATA).
+    pub nxdomain: bool,
+    /// Whether the answer was validated with DNSSEC by the upstream resolver
+    /// (AD bit), `None` if unknown
+    // https://datatracker.ietf.org/doc/html/rfc4035#section-3.2.3
+    pub authenticated: Option<bool>,
+}
+
+impl<T> Answer<T> {
+    /// Creates an answer with an unknown TTL and DNSSEC status
+    pub fn new(records: Vec<T>) -> Self {
+        Self {
+            records,
+            ttl: None,
+            nxdomain: false,
+            authenticated: None,
+        }
+    }
+
+    pub fn with_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn with_nxdomain(mut self, nxdomain: bool) -> Self {
+        self.nxdomain = nxdomain;
+        self
+    }
+
+    pub fn with_authenticated(mut self, authenticated: Option<bool>) -> Self {
+        self.authenticated = authenticated;
+        self
+    }
+}
 
 /// A trait for entities that perform DNS resolution.
 pub trait Lookup: Sync + Send {
     fn lookup_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DMARCError>>;
+
+    /// Same as `lookup_txt` but also returns the TTL of the answer
+    ///
+    /// By default the TTL is unknown.
+    fn lookup_txt_with_ttl<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Answer<String>, DMARCError>> {
+        Box::pin(async move { Ok(Answer::new(self.lookup_txt(name).await?)) })
+    }
+
+    /// Checks if the domain exists. As specified in
+    /// https://datatracker.ietf.org/doc/html/rfc9091#section-2.7 a domain
+    /// doesn't exist if the A, AAAA and MX queries return NXDOMAIN.
+    ///
+    /// By default domains are assumed to exist.
+    fn domain_exists<'a>(&'a self, _name: &'a str) -> BoxFuture<'a, Result<bool, DMARCError>> {
+        Box::pin(async { Ok(true) })
+    }
+
+    /// Returns the IPv4 addresses of the domain, empty if there's none or the
+    /// domain doesn't exist. Used by SPF.
+    ///
+    /// By default the lookup fails.
+    fn lookup_a<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<Ipv4Addr>, DMARCError>> {
+        Box::pin(async move { Err(unsupported("A", name)) })
+    }
+
+    /// Returns the IPv6 addresses of the domain, empty if there's none or the
+    /// domain doesn't exist. Used by SPF.
+    ///
+    /// By default the lookup fails.
+    fn lookup_aaaa<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<Ipv6Addr>, DMARCError>> {
+        Box::pin(async move { Err(unsupported("AAAA", name)) })
+    }
+
+    /// Returns the mail exchangers of the domain, by order of preference,
+    /// empty if there's none or the domain doesn't exist. Used by SPF.
+    ///
+    /// By default the lookup fails.
+    fn lookup_mx<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DMARCError>> {
+        Box::pin(async move { Err(unsupported("MX", name)) })
+    }
+
+    /// Returns the domains the IP address points to, empty if there's none.
+    /// Used by SPF.
+    ///
+    /// By default the lookup fails.
+    fn lookup_ptr(&self, ip: IpAddr) -> BoxFuture<'_, Result<Vec<String>, DMARCError>> {
+        Box::pin(async move { Err(unsupported("PTR", &ip.to_string())) })
+    }
+
+    /// Same as `lookup_a` but also returns the TTL of the answer
+    ///
+    /// By default the TTL is unknown.
+    fn lookup_a_with_ttl<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Answer<Ipv4Addr>, DMARCError>> {
+        Box::pin(async move { Ok(Answer::new(self.lookup_a(name).await?)) })
+    }
+
+    /// Same as `lookup_aaaa` but also returns the TTL of the answer
+    ///
+    /// By default the TTL is unknown.
+    fn lookup_aaaa_with_ttl<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Answer<Ipv6Addr>, DMARCError>> {
+        Box::pin(async move { Ok(Answer::new(self.lookup_aaaa(name).await?)) })
+    }
+
+    /// Same as `lookup_mx` but also returns the TTL of the answer
+    ///
+    /// By default the TTL is unknown.
+    fn lookup_mx_with_ttl<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Answer<String>, DMARCError>> {
+        Box::pin(async move { Ok(Answer::new(self.lookup_mx(name).await?)) })
+    }
+
+    /// Same as `lookup_ptr` but also returns the TTL of the answer
+    ///
+    /// By default the TTL is unknown.
+    fn lookup_ptr_with_ttl(&self, ip: IpAddr) -> BoxFuture<'_, Result<Answer<String>, DMARCError>> {
+        Box::pin(async move { Ok(Answer::new(self.lookup_ptr(ip).await?)) })
+    }
+}
+
+fn unsupported(record_type: &str, name: &str) -> DMARCError {
+    DMARCError::UnknownInternalError(format!(
+        "{} lookups aren't supported by the resolver: {}",
+        record_type, name
+    ))
+}
+
+/// Converts the records of a lookup and their expiration to an answer. The
+/// absence of records (NODATA or NXDOMAIN) isn't an error.
+fn to_answer<T>(res: Result<(Vec<T>, Instant), ResolveError>) -> Result<Answer<T>, DMARCError> {
+    match res {
+        Ok((records, valid_until)) => Ok(Answer::new(records)
+            .with_ttl(Some(valid_until.saturating_duration_since(Instant::now())))),
+        Err(err) => match err.kind() {
+            ResolveErrorKind::NoRecordsFound {
+                negative_ttl,
+                response_code,
+                ..
+            } => Ok(Answer::new(vec![])
+                .with_ttl(negative_ttl.map(|ttl| Duration::from_secs(ttl.into())))
+                .with_nxdomain(*response_code == ResponseCode::NXDomain)),
+            _ => Err(DMARCError::UnknownInternalError(format!(
+                "failed to query DNS: {}",
+                err
+            ))),
+        },
+    }
+}
+
+/// Formats a domain name without the trailing dot
+fn name_to_string(name: &trust_dns_resolver::Name) -> String {
+    name.to_utf8().trim_end_matches('.').to_owned()
 }
 
 // Technically we should be able to implemement Lookup for TokioAsyncResolver
 // directly but it's failing for some reason.
 struct TokioAsyncResolverWrapper {
     inner: TokioAsyncResolver,
+    /// Name servers queried directly for the TXT records, as the resolver
+    /// doesn't expose the AD bit of the responses
+    name_servers: Option<GenericNameServerPool<TokioRuntimeProvider>>,
+}
+
+impl TokioAsyncResolverWrapper {
+    /// Queries the TXT records of the name with the DO bit, returns the
+    /// records, their expiration and the AD bit of the response
+    async fn query_txt(
+        name_servers: &GenericNameServerPool<TokioRuntimeProvider>,
+        name: &str,
+    ) -> Result<(Vec<String>, Instant, bool), ResolveError> {
+        let mut name = Name::from_utf8(name)?;
+        name.set_fqdn(true);
+
+        let mut edns = Edns::new();
+        edns.set_dnssec_ok(true).set_max_payload(1232);
+        let mut message = Message::new();
+        message
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(true)
+            .set_authentic_data(true)
+            .add_query(Query::query(name, RecordType::TXT))
+            .set_edns(edns);
+        let request = DnsRequest::new(message, DnsRequestOptions::default());
+
+        let response = name_servers.clone().send(request).first_answer().await?;
+        let now = Instant::now();
+        let mut records = vec![];
+        let mut ttl = u32::MAX;
+        for record in response.answers() {
+            if let Some(RData::TXT(txt)) = record.data() {
+                records.push(
+                    txt.iter()
+                        .map(|data| String::from_utf8_lossy(data))
+                        .collect(),
+                );
+                ttl = ttl.min(record.ttl());
+            }
+        }
+        let valid_until = now + Duration::from_secs(ttl.min(86400).into());
+        Ok((records, valid_until, response.authentic_data()))
+    }
 }
+
 impl Lookup for TokioAsyncResolverWrapper {
     fn lookup_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DMARCError>> {
+        Box::pin(async move { Ok(self.lookup_txt_with_ttl(name).await?.records) })
+    }
+
+    fn lookup_txt_with_ttl<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Answer<String>, DMARCError>> {
         Box::pin(async move {
-            let res = self.inner.txt_lookup(name).await;
-            match res {
-                Ok(res) => {
-                    let records: Vec<String> = res
-                        .into_iter()
-                        .map(|txt| {
-                            txt.iter()
-                                .map(|data| String::from_utf8_lossy(data))
-                                .collect()
-                        })
-                        .collect();
-                    Ok(records)
-                }
-                Err(err) => match err.kind() {
-                    ResolveErrorKind::NoRecordsFound { .. } => Ok(vec![]),
-                    _ => Err(DMARCError::UnknownInternalError(format!(
-                        "failed to query DNS: {}",
-                        err
-                    ))),
-                },
+            if let Some(name_servers) = &self.name_servers {
+                let res = Self::query_txt(name_servers, name).await;
+                let authenticated = res.as_ref().ok().map(|(_, _, ad)| *ad);
+                let res = res.map(|(records, valid_until, _)| (records, valid_until));
+                return Ok(to_answer(res)?.with_authenticated(authenticated));
             }
+
+            let res = self.inner.txt_lookup(name).await.map(|res| {
+                let valid_until = res.valid_until();
+                let records = res
+                    .into_iter()
+                    .map(|txt| {
+                        txt.iter()
+                            .map(|data| String::from_utf8_lossy(data))
+                            .collect()
+                    })
+                    .collect();
+                (records, valid_until)
+            });
+            to_answer(res)
+        })
+    }
+
+    fn domain_exists<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<bool, DMARCError>> {
+        Box::pin(async move {
+            let nxdomain = self.lookup_a_with_ttl(name).await?.nxdomain
+                && self.lookup_aaaa_with_ttl(name).await?.nxdomain
+                && self.lookup_mx_with_ttl(name).await?.nxdomain;
+            Ok(!nxdomain)
+        })
+    }
+
+    fn lookup_a<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<Ipv4Addr>, DMARCError>> {
+        Box::pin(async move { Ok(self.lookup_a_with_ttl(name).await?.records) })
+    }
+
+    fn lookup_aaaa<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<Ipv6Addr>, DMARCError>> {
+        Box::pin(async move { Ok(self.lookup_aaaa_with_ttl(name).await?.records) })
+    }
+
+    fn lookup_mx<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DMARCError>> {
+        Box::pin(async move { Ok(self.lookup_mx_with_ttl(name).await?.records) })
+    }
+
+    fn lookup_ptr(&self, ip: IpAddr) -> BoxFuture<'_, Result<Vec<String>, DMARCError>> {
+        Box::pin(async move { Ok(self.lookup_ptr_with_ttl(ip).await?.records) })
+    }
+
+    fn lookup_a_with_ttl<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Answer<Ipv4Addr>, DMARCError>> {
+        Box::pin(async move {
+            let res = self.inner.ipv4_lookup(name).await;
+            to_answer(res.map(|res| (res.iter().map(|a| a.0).collect(), res.valid_until())))
+        })
+    }
+
+    fn lookup_aaaa_with_ttl<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Answer<Ipv6Addr>, DMARCError>> {
+        Box::pin(async move {
+            let res = self.inner.ipv6_lookup(name).await;
+            to_answer(res.map(|res| (res.iter().map(|aaaa| aaaa.0).collect(), res.valid_until())))
+        })
+    }
+
+    fn lookup_mx_with_ttl<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Answer<String>, DMARCError>> {
+        Box::pin(async move {
+            let res = self.inner.mx_lookup(name).await.map(|res| {
+                let mut mxs: Vec<_> = res.iter().collect();
+                mxs.sort_by_key(|mx| mx.preference());
+                let records = mxs.iter().map(|mx| name_to_string(mx.exchange())).collect();
+                (records, res.valid_until())
+            });
+            to_answer(res)
+        })
+    }
+
+    fn lookup_ptr_with_ttl(&self, ip: IpAddr) -> BoxFuture<'_, Result<Answer<String>, DMARCError>> {
+        Box::pin(async move {
+            let res = self.inner.reverse_lookup(ip).await.map(|res| {
+                let records = res.iter().map(|ptr| name_to_string(&ptr.0)).collect();
+                (records, res.valid_until())
+            });
+            to_answer(res)
         })
     }
 }
 
+/// Wraps the resolver, the DNSSEC status of its answers is unknown
 pub fn from_tokio_resolver(resolver: TokioAsyncResolver) -> Arc<dyn Lookup> {
-    Arc::new(TokioAsyncResolverWrapper { inner: resolver })
+    Arc::new(TokioAsyncResolverWrapper {
+        inner: resolver,
+        name_servers: None,
+    })
+}
+
+/// Creates a resolver reporting whether the TXT answers were validated with
+/// DNSSEC by the name servers of the configuration, which must be validating
+/// resolvers
+pub fn from_tokio_config(config: ResolverConfig, options: ResolverOpts) -> Arc<dyn Lookup> {
+    let name_servers = NameServerPool::from_config(
+        NameServerConfigGroup::from(config.name_servers().to_vec()),
+        &options,
+        GenericConnector::new(TokioRuntimeProvider::new()),
+    );
+    Arc::new(TokioAsyncResolverWrapper {
+        inner: TokioAsyncResolver::tokio(config, options),
+        name_servers: Some(name_servers),
+    })
+}
+
+/// Adapts the resolver to the cfdkim crate, so that the DKIM keys are queried
+/// with the same resolver (and cache) as the policies
+pub fn to_dkim_resolver(resolver: Arc<dyn Lookup>) -> Arc<dyn cfdkim::dns::Lookup> {
+    Arc::new(DkimResolver { inner: resolver })
+}
+
+struct DkimResolver {
+    inner: Arc<dyn Lookup>,
+}
+impl cfdkim::dns::Lookup for DkimResolver {
+    fn lookup_txt<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<String>, cfdkim::DKIMError>> {
+        Box::pin(async move {
+            match self.inner.lookup_txt(name).await {
+                Ok(records) if records.is_empty() => Err(cfdkim::DKIMError::NoKeyForSignature),
+                Ok(records) => Ok(records),
+                Err(err) => Err(cfdkim::DKIMError::KeyUnavailable(err.to_string())),
+            }
+        })
+    }
 }
 
 // https://datatracker.ietf.org/doc/html/rfc7489#section-3.2
@@ -55,3 +404,258 @@ pub(crate) fn get_root_domain_name(domain: &str) -> Option<String> {
         None
     }
 }
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+    use std::collections::{HashMap, HashSet};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// In-memory resolver used by the tests
+    #[derive(Default)]
+    pub(crate) struct TestResolver {
+        txt: HashMap<String, Vec<String>>,
+        a: HashMap<String, Vec<Ipv4Addr>>,
+        aaaa: HashMap<String, Vec<Ipv6Addr>>,
+        mx: HashMap<String, Vec<String>>,
+        ptr: HashMap<IpAddr, Vec<String>>,
+        nxdomain: HashSet<String>,
+        ttl: Option<Duration>,
+        authenticated: Option<bool>,
+        queries: Arc<AtomicUsize>,
+    }
+
+    impl TestResolver {
+        pub(crate) fn txt(mut self, name: &str, record: &str) -> Self {
+            self.txt
+                .entry(name.to_owned())
+                .or_default()
+                .push(record.to_owned());
+            self
+        }
+
+        /// Adds an A or AAAA record
+        pub(crate) fn ip(mut self, name: &str, ip: &str) -> Self {
+            match ip.parse().unwrap() {
+                IpAddr::V4(ip) => self.a.entry(name.to_owned()).or_default().push(ip),
+                IpAddr::V6(ip) => self.aaaa.entry(name.to_owned()).or_default().push(ip),
+            }
+            self
+        }
+
+        pub(crate) fn mx(mut self, name: &str, exchange: &str) -> Self {
+            self.mx
+                .entry(name.to_owned())
+                .or_default()
+                .push(exchange.to_owned());
+            self
+        }
+
+        pub(crate) fn ptr(mut self, ip: &str, name: &str) -> Self {
+            self.ptr
+                .entry(ip.parse().unwrap())
+                .or_default()
+                .push(name.to_owned());
+            self
+        }
+
+        pub(crate) fn nxdomain(mut self, name: &str) -> Self {
+            self.nxdomain.insert(name.to_owned());
+            self
+        }
+
+        pub(crate) fn ttl(mut self, ttl: Duration) -> Self {
+            self.ttl = Some(ttl);
+            self
+        }
+
+        /// Answers as a validating resolver would for a signed zone
+        pub(crate) fn authenticated(mut self) -> Self {
+            self.authenticated = Some(true);
+            self
+        }
+
+        /// Returns a counter of the TXT queries
+        pub(crate) fn queries(&self) -> Arc<AtomicUsize> {
+            Arc::clone(&self.queries)
+        }
+
+        pub(crate) fn build(self) -> Arc<dyn Lookup> {
+            Arc::new(self)
+        }
+    }
+
+    impl Lookup for TestResolver {
+        fn lookup_txt<'a>(
+            &'a self,
+            name: &'a str,
+        ) -> BoxFuture<'a, Result<Vec<String>, DMARCError>> {
+            Box::pin(async move { Ok(self.lookup_txt_with_ttl(name).await?.records) })
+        }
+
+        fn lookup_txt_with_ttl<'a>(
+            &'a self,
+            name: &'a str,
+        ) -> BoxFuture<'a, Result<Answer<String>, DMARCError>> {
+            self.queries.fetch_add(1, Ordering::SeqCst);
+            let res = Answer::new(self.txt.get(name).cloned().unwrap_or_default())
+                .with_ttl(self.ttl)
+                .with_nxdomain(self.nxdomain.contains(name))
+                .with_authenticated(self.authenticated);
+            Box::pin(async move { Ok(res) })
+        }
+
+        fn domain_exists<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<bool, DMARCError>> {
+            let res = !self.nxdomain.contains(name);
+            Box::pin(async move { Ok(res) })
+        }
+
+        fn lookup_a<'a>(
+            &'a self,
+            name: &'a str,
+        ) -> BoxFuture<'a, Result<Vec<Ipv4Addr>, DMARCError>> {
+            let res = self.a.get(name).cloned().unwrap_or_default();
+            Box::pin(async move { Ok(res) })
+        }
+
+        fn lookup_aaaa<'a>(
+            &'a self,
+            name: &'a str,
+        ) -> BoxFuture<'a, Result<Vec<Ipv6Addr>, DMARCError>> {
+            let res = self.aaaa.get(name).cloned().unwrap_or_default();
+            Box::pin(async move { Ok(res) })
+        }
+
+        fn lookup_mx<'a>(
+            &'a self,
+            name: &'a str,
+        ) -> BoxFuture<'a, Result<Vec<String>, DMARCError>> {
+            let res = self.mx.get(name).cloned().unwrap_or_default();
+            Box::pin(async move { Ok(res) })
+        }
+
+        fn lookup_ptr(&self, ip: IpAddr) -> BoxFuture<'_, Result<Vec<String>, DMARCError>> {
+            let res = self.ptr.get(&ip).cloned().unwrap_or_default();
+            Box::pin(async move { Ok(res) })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Resolver implementing only the required method and A lookups
+    struct MinimalResolver;
+    impl Lookup for MinimalResolver {
+        fn lookup_txt<'a>(
+            &'a self,
+            _name: &'a str,
+        ) -> BoxFuture<'a, Result<Vec<String>, DMARCError>> {
+            Box::pin(async { Ok(vec!["v=DMARC1; p=none".to_owned()]) })
+        }
+
+        fn lookup_a<'a>(
+            &'a self,
+            _name: &'a str,
+        ) -> BoxFuture<'a, Result<Vec<Ipv4Addr>, DMARCError>> {
+            Box::pin(async { Ok(vec![Ipv4Addr::new(192, 0, 2, 1)]) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_default_methods() {
+        let resolver = MinimalResolver;
+        assert_eq!(
+            resolver
+                .lookup_txt_with_ttl("_dmarc.example.com")
+                .await
+                .unwrap(),
+            Answer::new(vec!["v=DMARC1; p=none".to_owned()])
+        );
+        let answer = resolver.lookup_a_with_ttl("example.com").await.unwrap();
+        assert_eq!(answer.records, vec![Ipv4Addr::new(192, 0, 2, 1)]);
+        assert_eq!(answer.ttl, None);
+        assert!(!answer.nxdomain);
+        assert_eq!(answer.authenticated, None);
+
+        assert!(resolver.lookup_mx_with_ttl("example.com").await.is_err());
+        assert!(resolver
+            .lookup_ptr_with_ttl("192.0.2.1".parse().unwrap())
+            .await
+            .is_err());
+        assert!(resolver.domain_exists("example.com").await.unwrap());
+    }
+
+    /// Recursive resolver answering with the AD bit for `signed.example`
+    async fn fake_resolver() -> std::net::SocketAddr {
+        use trust_dns_resolver::proto::rr::rdata::TXT;
+        use trust_dns_resolver::proto::rr::Record;
+
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 4096];
+            loop {
+                let (len, src) = socket.recv_from(&mut buf).await.unwrap();
+                let request = Message::from_vec(&buf[..len]).unwrap();
+                let query = request.queries()[0].clone();
+                let name = query.name().clone();
+
+                let mut response = Message::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(MessageType::Response)
+                    .set_op_code(OpCode::Query)
+                    .set_recursion_desired(true)
+                    .set_recursion_available(true)
+                    .set_authentic_data(
+                        request
+                            .extensions()
+                            .as_ref()
+                            .is_some_and(|edns| edns.dnssec_ok())
+                            && name.to_utf8().ends_with(".signed.example."),
+                    )
+                    .add_query(query)
+                    .add_answer(Record::from_rdata(
+                        name,
+                        300,
+                        RData::TXT(TXT::new(vec!["v=DMARC1; p=reject".to_owned()])),
+                    ));
+                socket
+                    .send_to(&response.to_vec().unwrap(), src)
+                    .await
+                    .unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_authenticated() {
+        use trust_dns_resolver::config::{NameServerConfig, Protocol};
+
+        let addr = fake_resolver().await;
+        let config = ResolverConfig::from_parts(
+            None,
+            vec![],
+            vec![NameServerConfig::new(addr, Protocol::Udp)],
+        );
+        let resolver = from_tokio_config(config, ResolverOpts::default());
+
+        let answer = resolver
+            .lookup_txt_with_ttl("_dmarc.signed.example")
+            .await
+            .unwrap();
+        assert_eq!(answer.records, vec!["v=DMARC1; p=reject"]);
+        assert_eq!(answer.authenticated, Some(true));
+        assert!(answer.ttl.unwrap() <= Duration::from_secs(300));
+
+        let answer = resolver
+            .lookup_txt_with_ttl("_dmarc.insecure.example")
+            .await
+            .unwrap();
+        assert_eq!(answer.records, vec!["v=DMARC1; p=reject"]);
+        assert_eq!(answer.authenticated, Some(false));
+    }
+}
@@ -1,5 +1,6 @@
 quick_error! {
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Clone)]
+    #[non_exhaustive]
     /// DMARC errors
     pub enum DMARCError {
         PolicyParseError(err: String) {
@@ -11,8 +12,52 @@ quick_error! {
         IncompatibleVersion(value: String) {
             display("incompatible version: {}", value)
         }
+        InvalidPolicy(err: String) {
+            display("invalid policy: {}", err)
+        }
+        InvalidReport(err: String) {
+            display("invalid aggregate report: {}", err)
+        }
+        InvalidPublicSuffixList(err: String) {
+            display("invalid public suffix list: {}", err)
+        }
+        InvalidMessage(err: String) {
+            display("invalid message: {}", err)
+        }
+        InvalidFromHeader(err: FromHeaderError) {
+            display("invalid From header: {}", err)
+            from()
+        }
         UnknownInternalError(err: String) {
             display("internal error: {}", err)
         }
     }
 }
+
+quick_error! {
+    #[derive(Debug, PartialEq, Clone)]
+    /// Errors extracting the RFC5322.From domain(s)
+    pub enum FromHeaderError {
+        MissingFromHeader {
+            display("missing From header")
+        }
+        MultipleFromHeaders(count: usize) {
+            display("found {} From headers", count)
+        }
+        EmptyFromHeader {
+            display("From header has no mailbox")
+        }
+        MultipleAuthorDomains(domains: Vec<String>) {
+            display("multiple author domains: {}", domains.join(", "))
+        }
+        MissingDomain(mailbox: String) {
+            display("missing domain in mailbox: {}", mailbox)
+        }
+        InvalidDomain(domain: String) {
+            display("invalid author domain: {}", domain)
+        }
+        EncodedWord(mailbox: String) {
+            display("encoded word in mailbox: {}", mailbox)
+        }
+    }
+}
@@ -0,0 +1,340 @@
+/// Extraction of the RFC5322.From domain(s) from raw message headers
+///
+/// Implements the rules from
+/// https://datatracker.ietf.org/doc/html/rfc7489#section-6.6.1
+use crate::FromHeaderError;
+
+/// What to do when a message has more than one author
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum MultipleAuthors {
+    /// Refuse to extract an author domain, the message should be rejected
+    #[default]
+    Reject,
+    /// Return every author domain so that DMARC can be evaluated for each
+    EvaluateEach,
+}
+
+/// Options controlling how the RFC5322.From header is interpreted
+#[derive(Debug, PartialEq, Clone)]
+pub struct FromHeaderOptions {
+    /// Behavior when the message contains multiple From header fields, which
+    /// is invalid as per RFC5322
+    pub multiple_headers: MultipleAuthors,
+    /// Behavior when a From header field contains multiple mailboxes with
+    /// different domains
+    pub multiple_mailboxes: MultipleAuthors,
+}
+
+impl Default for FromHeaderOptions {
+    fn default() -> Self {
+        Self {
+            multiple_headers: MultipleAuthors::Reject,
+            multiple_mailboxes: MultipleAuthors::EvaluateEach,
+        }
+    }
+}
+
+/// Extract the author domain(s) from the raw header section of a message
+///
+/// `headers` can be the header section alone or the full message, parsing
+/// stops at the first empty line. Domains are lowercased and deduplicated
+/// while preserving their order of appearance.
+pub fn extract_from_domains(
+    headers: &[u8],
+    options: &FromHeaderOptions,
+) -> Result<Vec<String>, FromHeaderError> {
+    let headers = String::from_utf8_lossy(headers);
+
+    let fields = find_from_fields(&headers);
+    if fields.is_empty() {
+        return Err(FromHeaderError::MissingFromHeader);
+    }
+    if fields.len() > 1 && options.multiple_headers == MultipleAuthors::Reject {
+        return Err(FromHeaderError::MultipleFromHeaders(fields.len()));
+    }
+
+    let mut domains: Vec<String> = vec![];
+    for field in &fields {
+        for domain in parse_mailbox_domains(field)? {
+            if !domains.contains(&domain) {
+                domains.push(domain);
+            }
+        }
+    }
+
+    // If all the mailboxes share the same domain it's evaluated only once
+    if domains.len() > 1 && options.multiple_mailboxes == MultipleAuthors::Reject {
+        return Err(FromHeaderError::MultipleAuthorDomains(domains));
+    }
+
+    Ok(domains)
+}
+
+/// Returns the unfolded value of every From header field
+fn find_from_fields(headers: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut current: Option<String> = None;
+
+    for line in headers.split('\n') {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if line.is_empty() {
+            break;
+        }
+
+        if line.starts_with(' ') || line.starts_with('\t') {
+            // Folded line, continuation of the previous field
+            if let Some(value) = current.as_mut() {
+                value.push_str(line);
+            }
+            continue;
+        }
+
+        if let Some(value) = current.take() {
+            fields.push(value);
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim_end().eq_ignore_ascii_case("from") {
+                current = Some(value.to_owned());
+            }
+        }
+    }
+    if let Some(value) = current.take() {
+        fields.push(value);
+    }
+
+    fields
+}
+
+/// Parses a mailbox-list (or a group) and returns the domain of each mailbox
+fn parse_mailbox_domains(value: &str) -> Result<Vec<String>, FromHeaderError> {
+    let mut mailboxes: Vec<String> = vec![];
+
+    // Text of the current mailbox outside of comments and angle brackets
+    let mut current = String::new();
+    // Content of the angle brackets of the current mailbox, if any
+    let mut angle: Option<String> = None;
+    let mut in_angle = false;
+    let mut in_quote = false;
+    let mut comment_depth = 0;
+
+    macro_rules! end_mailbox {
+        () => {
+            let addr = angle.take().unwrap_or_else(|| current.clone());
+            current.clear();
+            if !addr.trim().is_empty() {
+                mailboxes.push(addr);
+            }
+        };
+    }
+
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if comment_depth > 0 {
+            match c {
+                '\\' => {
+                    chars.next();
+                }
+                '(' => comment_depth += 1,
+                ')' => comment_depth -= 1,
+                _ => {}
+            }
+            continue;
+        }
+
+        // Start of a group, what we have seen so far is the display name
+        let group_start = c == ':' && !in_angle && angle.is_none() && !current.contains('@');
+
+        let buf = if in_angle {
+            angle.get_or_insert_with(String::new)
+        } else {
+            &mut current
+        };
+
+        if in_quote {
+            buf.push(c);
+            match c {
+                '\\' => {
+                    if let Some(c) = chars.next() {
+                        buf.push(c);
+                    }
+                }
+                '"' => in_quote = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match c {
+            '"' => {
+                in_quote = true;
+                buf.push(c);
+            }
+            '(' => comment_depth += 1,
+            '<' if !in_angle => {
+                in_angle = true;
+                angle = Some(String::new());
+            }
+            '>' if in_angle => in_angle = false,
+            ',' | ';' if !in_angle => {
+                end_mailbox!();
+            }
+            ':' if group_start => current.clear(),
+            _ => buf.push(c),
+        }
+    }
+    end_mailbox!();
+
+    if mailboxes.is_empty() {
+        return Err(FromHeaderError::EmptyFromHeader);
+    }
+
+    mailboxes
+        .iter()
+        .map(|mailbox| mailbox_domain(mailbox))
+        .collect()
+}
+
+/// Returns the domain of an addr-spec
+fn mailbox_domain(mailbox: &str) -> Result<String, FromHeaderError> {
+    let addr: String = mailbox.chars().filter(|c| !c.is_whitespace()).collect();
+
+    // Obsolete source route (<@a.com,@b.com:user@c.com>)
+    let addr = match addr.split_once(':') {
+        Some((route, addr)) if route.starts_with('@') => addr,
+        _ => &addr,
+    };
+
+    // Encoded words are only allowed in the display name, finding one in the
+    // address means it can't be trusted
+    if addr.contains("=?") && addr.contains("?=") {
+        return Err(FromHeaderError::EncodedWord(mailbox.trim().to_owned()));
+    }
+
+    let domain = match addr.rsplit_once('@') {
+        Some((_, domain)) => domain,
+        None => return Err(FromHeaderError::MissingDomain(mailbox.trim().to_owned())),
+    };
+    let domain = domain.strip_suffix('.').unwrap_or(domain).to_lowercase();
+
+    if domain.is_empty() {
+        return Err(FromHeaderError::MissingDomain(mailbox.trim().to_owned()));
+    }
+    if !is_valid_domain(&domain) {
+        return Err(FromHeaderError::InvalidDomain(domain));
+    }
+
+    Ok(domain)
+}
+
+/// Domain literals (`[192.0.2.1]`) aren't domains that could publish a policy
+fn is_valid_domain(domain: &str) -> bool {
+    domain.split('.').all(|label| {
+        !label.is_empty()
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extract(headers: &str) -> Result<Vec<String>, FromHeaderError> {
+        extract_from_domains(headers.as_bytes(), &FromHeaderOptions::default())
+    }
+
+    #[test]
+    fn test_extract_from_domains() {
+        assert_eq!(
+            extract("From: a@Example.com\r\n").unwrap(),
+            vec!["example.com"]
+        );
+        assert_eq!(
+            extract("Subject: hi\r\nFrom: \"Doe, John\" <john@a.com>\r\nTo: b@b.com\r\n").unwrap(),
+            vec!["a.com"]
+        );
+        assert_eq!(
+            extract("From: John (comment, with <b@b.com>)\r\n <john@a.com>\r\n\r\nFrom: x@x.com")
+                .unwrap(),
+            vec!["a.com"]
+        );
+        assert_eq!(
+            extract("from: =?utf-8?q?J=C3=B6rg?= <jorg@a.com.>\n").unwrap(),
+            vec!["a.com"]
+        );
+        assert_eq!(
+            extract("From: <@relay.com:john@a.com>\n").unwrap(),
+            vec!["a.com"]
+        );
+    }
+
+    #[test]
+    fn test_extract_from_domains_group() {
+        assert_eq!(
+            extract("From: Team: a@a.com, b@b.com;\n").unwrap(),
+            vec!["a.com", "b.com"]
+        );
+        assert_eq!(
+            extract("From: undisclosed-recipients:;\n").unwrap_err(),
+            FromHeaderError::EmptyFromHeader
+        );
+    }
+
+    #[test]
+    fn test_extract_from_domains_multiple_mailboxes() {
+        assert_eq!(extract("From: a@a.com, b@A.com\n").unwrap(), vec!["a.com"]);
+
+        let options = FromHeaderOptions {
+            multiple_mailboxes: MultipleAuthors::Reject,
+            ..FromHeaderOptions::default()
+        };
+        assert_eq!(
+            extract_from_domains(b"From: a@a.com, b@A.com\n", &options).unwrap(),
+            vec!["a.com"]
+        );
+        assert_eq!(
+            extract_from_domains(b"From: a@a.com, b@b.com\n", &options).unwrap_err(),
+            FromHeaderError::MultipleAuthorDomains(vec!["a.com".to_owned(), "b.com".to_owned()])
+        );
+    }
+
+    #[test]
+    fn test_extract_from_domains_multiple_headers() {
+        assert_eq!(
+            extract("From: a@a.com\nFrom: b@b.com\n").unwrap_err(),
+            FromHeaderError::MultipleFromHeaders(2)
+        );
+
+        let options = FromHeaderOptions {
+            multiple_headers: MultipleAuthors::EvaluateEach,
+            ..FromHeaderOptions::default()
+        };
+        assert_eq!(
+            extract_from_domains(b"From: a@a.com\nFrom: b@b.com\n", &options).unwrap(),
+            vec!["a.com", "b.com"]
+        );
+    }
+
+    #[test]
+    fn test_extract_from_domains_errors() {
+        assert_eq!(
+            extract("To: a@a.com\n").unwrap_err(),
+            FromHeaderError::MissingFromHeader
+        );
+        assert_eq!(
+            extract("From: John <john>\n").unwrap_err(),
+            FromHeaderError::MissingDomain("john".to_owned())
+        );
+        assert_eq!(
+            extract("From: john@[192.0.2.1]\n").unwrap_err(),
+            FromHeaderError::InvalidDomain("[192.0.2.1]".to_owned())
+        );
+        assert_eq!(
+            extract("From: =?utf-8?q?john=40a.com?=\n").unwrap_err(),
+            FromHeaderError::EncodedWord("=?utf-8?q?john=40a.com?=".to_owned())
+        );
+    }
+}
@@ -1,5 +1,4 @@
 // Implementation of https://datatracker.ietf.org/doc/html/rfc7489
-use slog::warn;
 use std::collections::HashMap;
 use std::sync::Arc;
 use trust_dns_resolver::TokioAsyncResolver;
@@ -7,26 +6,66 @@ use trust_dns_resolver::TokioAsyncResolver;
 #[macro_use]
 extern crate quick_error;
 
+mod cache;
+mod coalesce;
+mod discovery;
 pub mod dns;
 mod errors;
+mod from_header;
+mod lint;
+mod message;
+#[cfg(feature = "milter")]
+pub mod milter;
 mod parser;
 mod policy;
+#[cfg(feature = "policyd")]
+pub mod policyd;
+mod psl;
+mod record;
+#[cfg(feature = "report")]
+pub mod report;
 mod result;
-
-pub use errors::DMARCError;
-pub use policy::{Policy, ReceiverAction};
+#[cfg(feature = "spf")]
+pub mod spf;
+mod trace;
+mod verifier;
+
+pub use cache::{CacheOptions, CacheStats, PolicyCache};
+pub use coalesce::CoalescingResolver;
+pub use discovery::{
+    discover_policy_with_resolver, Discovery, DiscoveryDiagnostic, DiscoveryMode, DiscoveryOptions,
+};
+pub use errors::{DMARCError, FromHeaderError};
+pub use from_header::{extract_from_domains, FromHeaderOptions, MultipleAuthors};
+pub use lint::{lint_record, LintDiagnostic, LintKind, Severity};
+pub use message::{Envelope, MessageVerdict, NoSpf, ReceivedSpfHeader, SpfSource};
+pub use parser::Tag;
+pub use policy::{
+    Alignement, Policy, PublicSuffixDomain, RandomSampler, ReceiverAction, ReportUri, Sampler,
+};
+pub use psl::{OrgDomainResolver, PslSections};
+pub use record::{PolicyBuilder, RecordOptions};
 pub use result::DMARCResult;
+pub use trace::{AlignmentTrace, EvaluationTrace, PolicyTrace, SamplingTrace};
+pub use verifier::DmarcVerifier;
 
 const DNS_SUBDOMAIN: &str = "_dmarc";
 
-/// Since the SPF crate we are using (visaspf) doesn't expose a result struct
-/// with the domain that it used, we'll use our own.
+/// Result of the SPF verification with the domain that was checked, which is
+/// needed for the alignment. With the `spf` feature it's computed by
+/// `spf::SpfVerifier`, otherwise callers provide their own.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SPFResult {
     pub domain_used: String,
     pub value: String,
 }
 
 /// Context needed to run a DMARC policy
+///
+/// Created with `PolicyContext::new`, the optional fields are set with the
+/// `with_` methods.
+#[non_exhaustive]
 pub struct PolicyContext<'a> {
     /// Result of the DKIM verification
     pub dkim_result: cfdkim::DKIMResult,
@@ -34,11 +73,60 @@ pub struct PolicyContext<'a> {
     pub spf_result: SPFResult,
     /// RFC5322.From's domain
     pub from_domain: &'a str,
+    /// Whether the RFC5322.From's domain exists in the DNS, `None` if it
+    /// wasn't checked. Used to apply the `np` tag.
+    pub from_domain_exists: Option<bool>,
+    /// Resolver of Organizational Domains used for relaxed alignment, the
+    /// Public Suffix List compiled in the crate is used if `None`
+    pub org_domain_resolver: Option<&'a OrgDomainResolver>,
+    /// Sampler applying the `pct` tag, messages are sampled randomly if
+    /// `None`
+    pub sampler: Option<&'a dyn Sampler>,
     /// Logger for debugging
     pub logger: &'a slog::Logger,
 }
 
+impl<'a> PolicyContext<'a> {
+    pub fn new(
+        from_domain: &'a str,
+        dkim_result: cfdkim::DKIMResult,
+        spf_result: SPFResult,
+        logger: &'a slog::Logger,
+    ) -> Self {
+        Self {
+            dkim_result,
+            spf_result,
+            from_domain,
+            from_domain_exists: None,
+            org_domain_resolver: None,
+            sampler: None,
+            logger,
+        }
+    }
+
+    /// Sets whether the RFC5322.From's domain exists, to apply the `np` tag
+    pub fn with_from_domain_exists(mut self, exists: bool) -> Self {
+        self.from_domain_exists = Some(exists);
+        self
+    }
+
+    /// Sets the resolver of Organizational Domains
+    pub fn with_org_domain_resolver(mut self, resolver: &'a OrgDomainResolver) -> Self {
+        self.org_domain_resolver = Some(resolver);
+        self
+    }
+
+    /// Sets the sampler applying the `pct` tag
+    pub fn with_sampler(mut self, sampler: &'a dyn Sampler) -> Self {
+        self.sampler = Some(sampler);
+        self
+    }
+}
+
 /// Load the DMARC policy for the domain
+///
+/// A new resolver is created from the system configuration for each call,
+/// applications loading many policies should use a `DmarcVerifier` instead.
 pub async fn load_policy<'a>(
     logger: &'a slog::Logger,
     from_domain: &'a str,
@@ -57,29 +145,30 @@ pub async fn load_policy_with_resolver<'a>(
     logger: &'a slog::Logger,
     from_domain: &'a str,
 ) -> Result<Option<policy::Policy>, DMARCError> {
-    macro_rules! load {
-        ($name:expr, $is_root:expr) => {
-            for record in resolver.lookup_txt(&$name).await? {
-                if record.starts_with("v=") {
-                    match parse_policy(&record, $is_root) {
-                        Ok(policy) => return Ok(Some(policy)),
-                        Err(err) => warn!(logger, "DMARC policy parse error: {}", err),
-                    }
-                }
-            }
-        };
-    }
-
-    // Search DMARC policy at the current domain
-    load!(format!("{}.{}", DNS_SUBDOMAIN, from_domain), false);
-
-    // No policy was found, if the domain was a subdomain try at the root domain
-    if let Some(root) = dns::get_root_domain_name(from_domain) {
-        load!(format!("{}.{}", DNS_SUBDOMAIN, root), true);
-    }
+    let discovery =
+        discover_policy_with_resolver(resolver, logger, from_domain, &DiscoveryOptions::default())
+            .await?;
+    Ok(discovery.policy)
+}
 
-    // Finally, if no policy was found return nothing
-    Ok(None)
+/// Evaluate DMARC for a message that has one or more author domains
+///
+/// As allowed by https://datatracker.ietf.org/doc/html/rfc7489#section-6.6.1
+/// the policy of each domain is loaded and applied, and the most restrictive
+/// result is returned. Domains without a policy result in `none`.
+///
+/// When the policy has a `np` tag, the existence of the domain is checked to
+/// determine if it applies.
+pub async fn evaluate_author_domains<'a>(
+    resolver: Arc<dyn dns::Lookup>,
+    logger: &'a slog::Logger,
+    from_domains: &'a [String],
+    dkim_result: &'a cfdkim::DKIMResult,
+    spf_result: &'a SPFResult,
+) -> Result<DMARCResult, DMARCError> {
+    DmarcVerifier::new(resolver)
+        .evaluate(logger, from_domains, dkim_result, spf_result)
+        .await
 }
 
 /// Parse a DMARC policy
@@ -107,25 +196,37 @@ fn parse_policy(record: &str, is_root: bool) -> Result<policy::Policy, DMARCErro
         }
     }
 
-    let action = if is_root {
-        let p = tags_map
-            .get("p")
-            .ok_or(DMARCError::MissingRequiredTag("p"))?;
-
-        if let Some(sp) = tags_map.get("sp") {
-            sp
-        } else {
-            p
-        }
-    } else {
-        tags_map
-            .get("p")
-            .ok_or(DMARCError::MissingRequiredTag("p"))?
+    let rua = tags_map
+        .get("rua")
+        .map(|v| parser::parse_report_uris(v))
+        .unwrap_or_default();
+
+    let p = tags_map
+        .get("p")
+        .ok_or(DMARCError::MissingRequiredTag("p"))
+        .and_then(|v| parser::parse_receiver_action(v));
+    let sp = tags_map.get("sp").map(|v| parser::parse_receiver_action(v));
+
+    let (p, sp, repaired) = match (p, sp.transpose()) {
+        (Ok(p), Ok(sp)) => (p, sp, false),
+        // A record with an invalid `p` or `sp` tag but a valid `rua` tag is
+        // treated as `p=none` so that the reports are still sent, the other
+        // tags still apply.
+        // https://datatracker.ietf.org/doc/html/rfc7489#section-6.6.3
+        (Err(_), _) | (_, Err(_)) if !rua.is_empty() => (policy::ReceiverAction::None, None, true),
+        (Err(err), _) | (_, Err(err)) => return Err(err),
     };
 
-    let action = parser::parse_receiver_action(action)?;
+    let action = match &sp {
+        Some(sp) if is_root => sp.clone(),
+        _ => p.clone(),
+    };
 
     let mut policy = policy::Policy::new(action);
+    policy.p = p;
+    policy.sp = sp;
+    policy.rua = rua;
+    policy.repaired = repaired;
 
     if let Some(v) = tags_map.get("adkim") {
         policy.adkim = parser::parse_alignement_mode(v);
@@ -136,34 +237,170 @@ fn parse_policy(record: &str, is_root: bool) -> Result<policy::Policy, DMARCErro
     if let Some(v) = tags_map.get("pct") {
         policy.pct = parser::parse_percentage(v);
     }
+    if let Some(v) = tags_map.get("np") {
+        policy.np = parser::parse_receiver_action(v).ok();
+    }
+    if let Some(v) = tags_map.get("psd") {
+        policy.psd = parser::parse_psd_flag(v);
+    }
+    if let Some(v) = tags_map.get("t") {
+        policy.testing = parser::parse_testing_mode(v);
+    }
+    if let Some(v) = tags_map.get("ruf") {
+        policy.ruf = parser::parse_report_uris(v);
+    }
+    if let Some(v) = tags_map
+        .get("fo")
+        .and_then(|v| parser::parse_failure_options(v))
+    {
+        policy.fo = v;
+    }
+    if let Some(v) = tags_map.get("ri").and_then(|v| v.parse().ok()) {
+        policy.ri = v;
+    }
 
+    policy.record = record.to_owned();
+    policy.tags = tags;
     Ok(policy)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use futures::future::BoxFuture;
-    use policy::{Alignement, Policy, ReceiverAction};
+    use policy::{Alignement, Policy, PublicSuffixDomain, ReceiverAction};
     use std::collections::HashMap;
 
     #[test]
     fn test_parse_policy() {
+        let record = "v=DMARC1;p=none;sp=quarantine;pct=67;rua=mailto:dmarcreports@example.com;";
+        let policy = parse_policy(record, false).unwrap();
         assert_eq!(
-            parse_policy(
-                "v=DMARC1;p=none;sp=quarantine;pct=67;rua=mailto:dmarcreports@example.com;",
-                false
-            )
-            .unwrap(),
+            policy,
             Policy {
                 adkim: Alignement::Relaxed,
                 aspf: Alignement::Relaxed,
                 pct: 67,
-                action: ReceiverAction::None
+                action: ReceiverAction::None,
+                p: ReceiverAction::None,
+                sp: Some(ReceiverAction::Quarantine),
+                np: None,
+                psd: PublicSuffixDomain::Unknown,
+                testing: false,
+                rua: vec![policy::ReportUri {
+                    uri: "mailto:dmarcreports@example.com".to_owned(),
+                    max_size: None,
+                }],
+                ruf: vec![],
+                fo: "0".to_owned(),
+                ri: 86400,
+                repaired: false,
+                record: record.to_owned(),
+                tags: vec![
+                    ("v", "DMARC1"),
+                    ("p", "none"),
+                    ("sp", "quarantine"),
+                    ("pct", "67"),
+                    ("rua", "mailto:dmarcreports@example.com"),
+                ]
+                .into_iter()
+                .map(|(name, value)| Tag {
+                    name: name.to_owned(),
+                    value: value.to_owned(),
+                    raw_value: value.to_owned(),
+                })
+                .collect(),
             }
         );
     }
 
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_policy() {
+        let policy = parse_policy(
+            "v=DMARC1; p=reject; sp=none; adkim=s; psd=y; rua=mailto:a@example.com!1k",
+            false,
+        )
+        .unwrap();
+
+        let json = serde_json::to_value(&policy).unwrap();
+        assert_eq!(json["action"], "reject");
+        assert_eq!(json["sp"], "none");
+        assert_eq!(json["np"], serde_json::Value::Null);
+        assert_eq!(json["adkim"], "s");
+        assert_eq!(json["aspf"], "r");
+        assert_eq!(json["psd"], "y");
+        assert_eq!(json["rua"][0]["max_size"], 1024);
+        assert_eq!(json["tags"][1]["name"], "p");
+
+        let back: Policy = serde_json::from_value(json).unwrap();
+        assert_eq!(back, policy);
+
+        let spf_result = SPFResult {
+            domain_used: "a.com".to_owned(),
+            value: "pass".to_owned(),
+        };
+        let json = serde_json::to_value(&spf_result).unwrap();
+        assert_eq!(json["domain_used"], "a.com");
+    }
+
+    #[test]
+    fn test_parse_policy_unknown_tags() {
+        let record = "v=DMARC1; p=none; xfuture=a b; pct=50";
+        let policy = parse_policy(record, false).unwrap();
+        assert_eq!(policy.record, record);
+        assert_eq!(policy.tags.len(), 4);
+        let tag = policy.tag("xfuture").unwrap();
+        assert_eq!(tag.value, "ab");
+        assert_eq!(tag.raw_value, "a b");
+        assert!(policy.tag("sp").is_none());
+    }
+
+    #[test]
+    fn test_parse_policy_repaired() {
+        let policy = parse_policy("v=DMARC1;p=hein;rua=mailto:a@example.com", false).unwrap();
+        assert_eq!(policy.action, ReceiverAction::None);
+        assert_eq!(policy.rua.len(), 1);
+        assert!(policy.repaired);
+
+        // Only `sp` is published
+        let policy = parse_policy("v=DMARC1;sp=reject;rua=mailto:a@example.com", true).unwrap();
+        assert_eq!(policy.action, ReceiverAction::None);
+        assert!(policy.repaired);
+
+        let policy =
+            parse_policy("v=DMARC1;p=reject;sp=hein;rua=mailto:a@example.com", false).unwrap();
+        assert_eq!(policy.action, ReceiverAction::None);
+        assert!(policy.repaired);
+
+        // The other tags are kept
+        let policy = parse_policy(
+            "v=DMARC1;p=hein;adkim=s;pct=50;rua=mailto:a@example.com",
+            false,
+        )
+        .unwrap();
+        assert!(policy.repaired);
+        assert_eq!(policy.action, ReceiverAction::None);
+        assert_eq!(policy.adkim, Alignement::Strict);
+        assert_eq!(policy.pct, 50);
+
+        // Without a valid `rua` the record is ignored
+        assert!(parse_policy("v=DMARC1;p=hein;rua=hein", false).is_err());
+        assert!(parse_policy("v=DMARC1;p=reject;sp=hein", false).is_err());
+    }
+
+    #[test]
+    fn test_parse_policy_dmarcbis_tags() {
+        let policy = parse_policy("v=DMARC1;p=none;np=reject;psd=y;t=y", false).unwrap();
+        assert_eq!(policy.np, Some(ReceiverAction::Reject));
+        assert_eq!(policy.psd, PublicSuffixDomain::Yes);
+        assert!(policy.testing);
+
+        let policy = parse_policy("v=DMARC1;p=none;np=hein;psd=n;t=n", false).unwrap();
+        assert_eq!(policy.np, None);
+        assert_eq!(policy.psd, PublicSuffixDomain::No);
+        assert!(!policy.testing);
+    }
+
     #[test]
     fn test_parse_policy_invalid_version() {
         assert_eq!(
@@ -215,23 +452,12 @@ mod tests {
     }
 
     fn test_resolver(db: HashMap<&'static str, &'static str>) -> Arc<dyn dns::Lookup> {
-        struct TestResolver {
-            db: HashMap<&'static str, &'static str>,
-        }
-        impl dns::Lookup for TestResolver {
-            fn lookup_txt<'a>(
-                &'a self,
-                name: &'a str,
-            ) -> BoxFuture<'a, Result<Vec<String>, DMARCError>> {
-                let res = if let Some(value) = self.db.get(name) {
-                    vec![value.to_string()]
-                } else {
-                    vec![]
-                };
-                Box::pin(async move { Ok(res) })
-            }
-        }
-        Arc::new(TestResolver { db })
+        db.into_iter()
+            .fold(
+                dns::testing::TestResolver::default(),
+                |resolver, (name, record)| resolver.txt(name, record),
+            )
+            .build()
     }
 
     #[tokio::test]
@@ -255,6 +481,83 @@ mod tests {
         assert_eq!(policy.pct, 26);
     }
 
+    #[tokio::test]
+    async fn test_evaluate_author_domains() {
+        let resolver = test_resolver(map! {
+            "_dmarc.a.com" => "v=DMARC1; p=reject;",
+            "_dmarc.b.com" => "v=DMARC1; p=quarantine;",
+            "_dmarc.c.com" => "v=DMARC1; p=none;"
+        });
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let dkim_result = cfdkim::DKIMResult::neutral("a.com".to_owned());
+        let spf_result = SPFResult {
+            domain_used: "a.com".to_string(),
+            value: "pass".to_string(),
+        };
+
+        let evaluate = |domains: &[&str]| {
+            let domains: Vec<String> = domains.iter().map(|d| d.to_string()).collect();
+            let resolver = Arc::clone(&resolver);
+            let logger = &logger;
+            let dkim_result = &dkim_result;
+            let spf_result = &spf_result;
+            async move {
+                evaluate_author_domains(resolver, logger, &domains, dkim_result, spf_result)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let res = evaluate(&["a.com"]).await;
+        assert_eq!(res.to_str(), "pass");
+
+        let res = evaluate(&["a.com", "c.com"]).await;
+        assert_eq!(res.to_str(), "fail");
+        assert_eq!(res.disposition(), ReceiverAction::None);
+
+        let res = evaluate(&["c.com", "b.com", "a.com"]).await;
+        assert_eq!(res.to_str(), "fail");
+        assert_eq!(res.disposition(), ReceiverAction::Quarantine);
+
+        let res = evaluate(&["a.com", "unknown.com"]).await;
+        assert_eq!(res.to_str(), "pass");
+
+        let res = evaluate(&[]).await;
+        assert_eq!(res.to_str(), "none");
+    }
+
+    #[tokio::test]
+    async fn test_evaluate_author_domains_non_existent() {
+        let resolver = dns::testing::TestResolver::default()
+            .txt("_dmarc.a.com", "v=DMARC1; p=none; np=reject;")
+            .nxdomain("nx.a.com")
+            .build();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let dkim_result = cfdkim::DKIMResult::neutral("a.com".to_owned());
+        let spf_result = SPFResult {
+            domain_used: "b.com".to_string(),
+            value: "pass".to_string(),
+        };
+
+        let domains = vec!["exists.a.com".to_owned()];
+        let res = evaluate_author_domains(
+            Arc::clone(&resolver),
+            &logger,
+            &domains,
+            &dkim_result,
+            &spf_result,
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.disposition(), ReceiverAction::None);
+
+        let domains = vec!["nx.a.com".to_owned()];
+        let res = evaluate_author_domains(resolver, &logger, &domains, &dkim_result, &spf_result)
+            .await
+            .unwrap();
+        assert_eq!(res.disposition(), ReceiverAction::Reject);
+    }
+
     #[tokio::test]
     async fn test_load_policy_subdomain_no_policy() {
         let resolver = test_resolver(map! {
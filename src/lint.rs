@@ -0,0 +1,357 @@
+/// Linting of DMARC records
+use crate::parser;
+use std::collections::HashSet;
+use std::ops::Range;
+
+/// Tags defined by RFC7489 and DMARCbis
+const KNOWN_TAGS: &[&str] = &[
+    "v", "p", "sp", "np", "psd", "t", "adkim", "aspf", "pct", "fo", "ri", "rf", "rua", "ruf",
+];
+
+#[derive(Debug, PartialEq, Clone, Copy, PartialOrd, Ord, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Severity {
+    /// Not an error but likely a mistake
+    Warning,
+    /// The record or the tag is ignored by receivers
+    Error,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum LintKind {
+    /// The record doesn't follow the tag-list syntax
+    Syntax,
+    /// A required tag is missing
+    MissingTag,
+    /// The tag isn't defined by RFC7489 or DMARCbis
+    UnknownTag,
+    /// The tag appears more than once
+    DuplicateTag,
+    /// `v` isn't the first tag or `p` isn't the second
+    TagOrder,
+    /// Invalid value, ignored or replaced by its default
+    InvalidValue,
+    /// Tag names and values are case sensitive
+    Case,
+    /// Reporting URI without the `mailto:` scheme, which is the only one
+    /// receivers are required to support
+    NotMailto,
+}
+
+/// Problem found in a DMARC record
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LintDiagnostic {
+    pub severity: Severity,
+    pub kind: LintKind,
+    /// Name of the tag, as written in the record
+    pub tag: Option<String>,
+    /// Byte range of the problem in the record
+    pub span: Range<usize>,
+    pub message: String,
+}
+
+struct RawTag<'a> {
+    name: &'a str,
+    name_span: Range<usize>,
+    value: &'a str,
+    value_span: Range<usize>,
+}
+
+/// Returns the trimmed sub-slice with its byte range in the record
+fn trimmed(record: &str, span: Range<usize>) -> (&str, Range<usize>) {
+    let s = &record[span.clone()];
+    let start = span.start + (s.len() - s.trim_start().len());
+    let end = span.end - (s.len() - s.trim_end().len());
+    if start >= end {
+        return ("", span.start..span.start);
+    }
+    (&record[start..end], start..end)
+}
+
+/// Lints a DMARC record, returning all the problems found
+///
+/// Unlike the parsing, which fails on the first error or silently replaces
+/// invalid values by their default, every problem is reported with the tag
+/// and the byte range it applies to.
+pub fn lint_record(record: &str) -> Vec<LintDiagnostic> {
+    let mut diagnostics = vec![];
+    let mut tags = vec![];
+
+    let mut start = 0;
+    for part in record.split(';') {
+        let span = start..start + part.len();
+        start = span.end + 1;
+
+        let (part, span) = trimmed(record, span);
+        if part.is_empty() {
+            continue;
+        }
+        match part.find('=') {
+            Some(i) => {
+                let (name, name_span) = trimmed(record, span.start..span.start + i);
+                let (value, value_span) = trimmed(record, span.start + i + 1..span.end);
+                tags.push(RawTag {
+                    name,
+                    name_span,
+                    value,
+                    value_span,
+                });
+            }
+            None => diagnostics.push(LintDiagnostic {
+                severity: Severity::Error,
+                kind: LintKind::Syntax,
+                tag: None,
+                span,
+                message: format!("expected `tag=value`, found `{}`", part),
+            }),
+        }
+    }
+
+    let mut seen = HashSet::new();
+    for (i, tag) in tags.iter().enumerate() {
+        let diagnostic = |severity, kind, span: &Range<usize>, message: String| LintDiagnostic {
+            severity,
+            kind,
+            tag: Some(tag.name.to_owned()),
+            span: span.clone(),
+            message,
+        };
+
+        // The parser doesn't distinguish `p` and `P`
+        if !seen.insert(tag.name.to_lowercase()) {
+            diagnostics.push(diagnostic(
+                Severity::Error,
+                LintKind::DuplicateTag,
+                &tag.name_span,
+                format!("duplicate tag `{}`", tag.name),
+            ));
+            continue;
+        }
+
+        if !KNOWN_TAGS.contains(&tag.name) {
+            let lowercase = tag.name.to_lowercase();
+            if KNOWN_TAGS.contains(&lowercase.as_str()) {
+                diagnostics.push(diagnostic(
+                    Severity::Error,
+                    LintKind::Case,
+                    &tag.name_span,
+                    format!("tag names are case sensitive, use `{}`", lowercase),
+                ));
+            } else {
+                diagnostics.push(diagnostic(
+                    Severity::Warning,
+                    LintKind::UnknownTag,
+                    &tag.name_span,
+                    format!("unknown tag `{}`", tag.name),
+                ));
+            }
+            continue;
+        }
+
+        // https://datatracker.ietf.org/doc/html/rfc7489#section-6.4
+        match (tag.name, i) {
+            ("v", 0) | ("p", 1) => {}
+            ("v", _) => diagnostics.push(diagnostic(
+                Severity::Error,
+                LintKind::TagOrder,
+                &tag.name_span,
+                "`v` must be the first tag".to_owned(),
+            )),
+            ("p", _) => diagnostics.push(diagnostic(
+                Severity::Warning,
+                LintKind::TagOrder,
+                &tag.name_span,
+                "`p` should be the second tag".to_owned(),
+            )),
+            _ => {}
+        }
+
+        if let Some((kind, message)) = check_value(tag.name, tag.value) {
+            let severity = match tag.name {
+                // The record is ignored, or treated as `p=none`
+                "v" | "p" | "sp" => Severity::Error,
+                // The value is replaced by its default
+                _ => Severity::Warning,
+            };
+            diagnostics.push(diagnostic(severity, kind, &tag.value_span, message));
+        }
+    }
+
+    for required in ["v", "p"] {
+        if !seen.contains(required) {
+            diagnostics.push(LintDiagnostic {
+                severity: Severity::Error,
+                kind: LintKind::MissingTag,
+                tag: None,
+                span: 0..record.len(),
+                message: format!("missing required tag `{}`", required),
+            });
+        }
+    }
+
+    diagnostics
+}
+
+fn check_value(name: &str, value: &str) -> Option<(LintKind, String)> {
+    let allowed: &[&str] = match name {
+        "v" => &["DMARC1"],
+        "p" | "sp" | "np" => &["none", "quarantine", "reject"],
+        "adkim" | "aspf" => &["r", "s"],
+        "psd" => &["y", "n", "u"],
+        "t" => &["y", "n"],
+        "rf" => &["afrf"],
+        "pct" => {
+            return match value.parse::<usize>() {
+                Ok(v) if v <= 100 => None,
+                _ => Some((
+                    LintKind::InvalidValue,
+                    format!("`pct` must be between 0 and 100, found `{}`", value),
+                )),
+            };
+        }
+        "ri" => {
+            return match value.parse::<u32>() {
+                Ok(_) => None,
+                Err(_) => Some((
+                    LintKind::InvalidValue,
+                    format!("`ri` must be a number of seconds, found `{}`", value),
+                )),
+            };
+        }
+        "fo" => {
+            return match parser::parse_failure_options(value) {
+                Some(_) => None,
+                None => Some((
+                    LintKind::InvalidValue,
+                    format!("invalid failure reporting options `{}`", value),
+                )),
+            };
+        }
+        "rua" | "ruf" => return check_report_uris(name, value),
+        _ => return None,
+    };
+
+    if allowed.contains(&value) {
+        None
+    } else if allowed.iter().any(|v| v.eq_ignore_ascii_case(value)) {
+        Some((
+            LintKind::Case,
+            format!("values are case sensitive, found `{}`", value),
+        ))
+    } else {
+        Some((
+            LintKind::InvalidValue,
+            format!(
+                "invalid value `{}`, expected one of: {}",
+                value,
+                allowed.join(", ")
+            ),
+        ))
+    }
+}
+
+fn check_report_uris(name: &str, value: &str) -> Option<(LintKind, String)> {
+    let uris: Vec<&str> = value.split(',').map(|uri| uri.trim()).collect();
+    if let Some(uri) = uris
+        .iter()
+        .find(|uri| parser::parse_report_uri(uri).is_none())
+    {
+        return Some((
+            LintKind::InvalidValue,
+            format!("invalid reporting URI `{}` in `{}`", uri, name),
+        ));
+    }
+    let is_mailto = |uri: &&str| {
+        uri.get(..7)
+            .is_some_and(|scheme| scheme.eq_ignore_ascii_case("mailto:"))
+    };
+    if let Some(uri) = uris.iter().find(|uri| !is_mailto(uri)) {
+        return Some((
+            LintKind::NotMailto,
+            format!("`{}` URI without `mailto:` scheme: `{}`", name, uri),
+        ));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(record: &str) -> Vec<(LintKind, Option<String>)> {
+        lint_record(record)
+            .into_iter()
+            .map(|d| (d.kind, d.tag))
+            .collect()
+    }
+
+    #[test]
+    fn test_lint_valid() {
+        assert!(lint_record(
+            "v=DMARC1; p=reject; sp=none; adkim=s; pct=50; rua=mailto:a@example.com!10m;"
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_lint_spans() {
+        let record = "v=DMARC1; p=none; adkim=hein; pct=77777";
+        let diagnostics = lint_record(record);
+        assert_eq!(diagnostics.len(), 2);
+
+        assert_eq!(diagnostics[0].kind, LintKind::InvalidValue);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert_eq!(diagnostics[0].tag.as_deref(), Some("adkim"));
+        assert_eq!(&record[diagnostics[0].span.clone()], "hein");
+
+        assert_eq!(diagnostics[1].tag.as_deref(), Some("pct"));
+        assert_eq!(&record[diagnostics[1].span.clone()], "77777");
+    }
+
+    #[test]
+    fn test_lint_tags() {
+        assert_eq!(
+            kinds("v=DMARC1; p=none; foo=bar; p=reject; SP=none"),
+            vec![
+                (LintKind::UnknownTag, Some("foo".to_owned())),
+                (LintKind::DuplicateTag, Some("p".to_owned())),
+                (LintKind::Case, Some("SP".to_owned())),
+            ]
+        );
+        assert_eq!(
+            kinds("v=DMARC1; p=none; P=reject"),
+            vec![(LintKind::DuplicateTag, Some("P".to_owned()))]
+        );
+        assert_eq!(
+            kinds("p=none; v=DMARC1"),
+            vec![
+                (LintKind::TagOrder, Some("p".to_owned())),
+                (LintKind::TagOrder, Some("v".to_owned())),
+            ]
+        );
+        assert_eq!(
+            kinds("v=DMARC1; sp=none; hein"),
+            vec![(LintKind::Syntax, None), (LintKind::MissingTag, None)]
+        );
+    }
+
+    #[test]
+    fn test_lint_values() {
+        let diagnostics = lint_record("v=dmarc1; p=Reject; rua=https://example.com/r");
+        assert_eq!(diagnostics.len(), 3);
+        assert_eq!(diagnostics[0].kind, LintKind::Case);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!(diagnostics[1].kind, LintKind::Case);
+        assert_eq!(diagnostics[2].kind, LintKind::NotMailto);
+        assert_eq!(diagnostics[2].severity, Severity::Warning);
+
+        assert_eq!(
+            kinds("v=DMARC1; p=none; ruf=mailto:a@example.com,hein"),
+            vec![(LintKind::InvalidValue, Some("ruf".to_owned()))]
+        );
+    }
+}
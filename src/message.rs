@@ -0,0 +1,194 @@
+/// Verification of a whole message: DKIM, SPF and DMARC
+use crate::{policy, DMARCResult, EvaluationTrace, SPFResult};
+use futures::future::BoxFuture;
+use std::net::IpAddr;
+
+/// SMTP envelope of a message
+#[derive(Debug, PartialEq, Clone)]
+pub struct Envelope<'a> {
+    /// IP address of the SMTP client, `None` for local submissions
+    pub client_ip: Option<IpAddr>,
+    /// Domain of the SMTP HELO/EHLO command
+    pub helo: &'a str,
+    /// Address of the SMTP MAIL FROM command, empty for bounces
+    pub mail_from: &'a str,
+}
+
+impl<'a> Envelope<'a> {
+    /// MAIL FROM address without the angle brackets
+    pub fn mail_from_address(&self) -> &'a str {
+        self.mail_from.trim_matches(|c| c == '<' || c == '>')
+    }
+
+    /// Domain used by SPF: the MAIL FROM domain, or the HELO domain for
+    /// bounces
+    // https://datatracker.ietf.org/doc/html/rfc7208#section-2.4
+    pub fn spf_domain(&self) -> String {
+        let mail_from_domain = self
+            .mail_from_address()
+            .rsplit_once('@')
+            .map(|(_, domain)| domain.to_lowercase())
+            .filter(|domain| !domain.is_empty());
+        mail_from_domain.unwrap_or_else(|| self.helo.trim_end_matches('.').to_lowercase())
+    }
+}
+
+/// Source of the SPF result of a message
+pub trait SpfSource: Send + Sync {
+    /// Returns the SPF result, `None` if SPF wasn't checked
+    fn spf_result<'a>(
+        &'a self,
+        envelope: &'a Envelope<'a>,
+        raw_message: &'a [u8],
+    ) -> BoxFuture<'a, Option<SPFResult>>;
+}
+
+/// SPF isn't checked, DMARC relies on DKIM only
+pub struct NoSpf;
+
+impl SpfSource for NoSpf {
+    fn spf_result<'a>(
+        &'a self,
+        _envelope: &'a Envelope<'a>,
+        _raw_message: &'a [u8],
+    ) -> BoxFuture<'a, Option<SPFResult>> {
+        Box::pin(async { None })
+    }
+}
+
+/// Reads the SPF result from the first `Received-SPF` header, as added by
+/// the MTA's SPF check
+///
+/// The header must be added by the MTA before the verification, otherwise
+/// the one added by the sender would be trusted.
+// https://datatracker.ietf.org/doc/html/rfc7208#section-9.1
+pub struct ReceivedSpfHeader;
+
+impl SpfSource for ReceivedSpfHeader {
+    fn spf_result<'a>(
+        &'a self,
+        envelope: &'a Envelope<'a>,
+        raw_message: &'a [u8],
+    ) -> BoxFuture<'a, Option<SPFResult>> {
+        let value = mailparse::parse_headers(raw_message)
+            .ok()
+            .and_then(|(headers, _)| {
+                headers
+                    .iter()
+                    .find(|header| header.get_key_ref().eq_ignore_ascii_case("Received-SPF"))
+                    .map(|header| header.get_value())
+            })
+            .and_then(|value| {
+                value
+                    .split_whitespace()
+                    .next()
+                    .map(|value| value.to_lowercase())
+            });
+        let result = value.map(|value| SPFResult {
+            domain_used: envelope.spf_domain(),
+            value,
+        });
+        Box::pin(async move { result })
+    }
+}
+
+/// Outcome of the verification of a message
+pub struct MessageVerdict {
+    /// RFC5322.From domains
+    pub from_domains: Vec<String>,
+    /// Result of the DKIM verification of each author domain, in the same
+    /// order
+    pub dkim_results: Vec<cfdkim::DKIMResult>,
+    /// `None` if SPF wasn't checked
+    pub spf_result: Option<SPFResult>,
+    /// MAIL FROM address, without the angle brackets
+    pub mail_from: String,
+    /// Most restrictive result of the author domains
+    pub result: DMARCResult,
+    /// Author domain of the result
+    pub result_domain: String,
+    /// Trace of the evaluation of each author domain
+    pub traces: Vec<EvaluationTrace>,
+}
+
+impl MessageVerdict {
+    /// Returns the action requested by the domain owner for this message
+    pub fn disposition(&self) -> policy::ReceiverAction {
+        self.result.disposition()
+    }
+
+    /// Returns the `dkim`, `spf` and `dmarc` methods of the
+    /// `Authentication-Results` header
+    // https://datatracker.ietf.org/doc/html/rfc8601#section-2.7
+    pub fn authentication_methods(&self) -> Vec<String> {
+        let mut methods = vec![];
+        for dkim_result in &self.dkim_results {
+            methods.push(format!(
+                "dkim={} header.d={}",
+                dkim_result.with_detail(),
+                dkim_result.domain_used()
+            ));
+        }
+        if let Some(spf_result) = &self.spf_result {
+            if self.mail_from.is_empty() {
+                methods.push(format!(
+                    "spf={} smtp.helo={}",
+                    spf_result.value, spf_result.domain_used
+                ));
+            } else {
+                methods.push(format!(
+                    "spf={} smtp.mailfrom={}",
+                    spf_result.value, self.mail_from
+                ));
+            }
+        }
+        methods.push(self.result.authentication_results(&self.result_domain));
+        methods
+    }
+
+    /// Returns the value of the `Authentication-Results` header
+    pub fn authentication_results(&self, authserv_id: &str) -> String {
+        format!(
+            "{}; {}",
+            authserv_id,
+            self.authentication_methods().join("; ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope<'a>(helo: &'a str, mail_from: &'a str) -> Envelope<'a> {
+        Envelope {
+            client_ip: None,
+            helo,
+            mail_from,
+        }
+    }
+
+    #[test]
+    fn test_spf_domain() {
+        let envelope1 = envelope("mx.example", "<bounce@X.com>");
+        assert_eq!(envelope1.mail_from_address(), "bounce@X.com");
+        assert_eq!(envelope1.spf_domain(), "x.com");
+        assert_eq!(envelope("mx.example.", "<>").spf_domain(), "mx.example");
+        assert_eq!(envelope("", "").spf_domain(), "");
+    }
+
+    #[tokio::test]
+    async fn test_received_spf_header() {
+        let envelope = envelope("mx.example", "bounce@example.com");
+        let raw = b"Received-SPF: SoftFail (mx.local)\r\n\
+                    Received-SPF: pass\r\n\
+                    From: alice@example.com\r\n\r\n";
+        let result = ReceivedSpfHeader.spf_result(&envelope, raw).await.unwrap();
+        assert_eq!(result.value, "softfail");
+        assert_eq!(result.domain_used, "example.com");
+
+        let raw = b"From: alice@example.com\r\n\r\n";
+        assert!(ReceivedSpfHeader.spf_result(&envelope, raw).await.is_none());
+        assert!(NoSpf.spf_result(&envelope, raw).await.is_none());
+    }
+}
@@ -0,0 +1,677 @@
+/// Milter front-end, to enforce DMARC from Postfix or Sendmail
+///
+/// The MTA connects to the milter and sends the SMTP transaction (client IP,
+/// HELO, MAIL FROM, headers and body). At the end of each message, DKIM is
+/// verified, the DMARC policy of the author domains is applied and an
+/// `Authentication-Results` header is added. Messages failing DMARC are
+/// quarantined or rejected as requested by the domain owner.
+///
+/// The protocol is documented in libmilter's sources, see
+/// https://github.com/emersion/go-milter/blob/master/milter-protocol.txt
+use crate::{extract_from_domains, DMARCError, DmarcVerifier, ReceiverAction};
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+
+mod protocol;
+use protocol::*;
+
+const AUTHENTICATION_RESULTS: &str = "Authentication-Results";
+
+/// SMTP transaction and message received from the MTA
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Message {
+    /// IP address of the SMTP client
+    pub client_ip: Option<IpAddr>,
+    /// Host name of the SMTP client, as resolved by the MTA
+    pub client_name: Option<String>,
+    /// Domain of the SMTP HELO/EHLO command
+    pub helo: Option<String>,
+    /// Address of the SMTP MAIL FROM command, without the angle brackets
+    pub mail_from: Option<String>,
+    /// Header fields, with the value as it appears after the colon
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Message {
+    /// Domain used by SPF: the MAIL FROM domain, or the HELO domain for
+    /// bounces
+    // https://datatracker.ietf.org/doc/html/rfc7208#section-2.4
+    pub fn spf_domain(&self) -> Option<String> {
+        let mail_from_domain = self
+            .mail_from
+            .as_deref()
+            .and_then(|mail_from| mail_from.rsplit_once('@'))
+            .map(|(_, domain)| domain.to_lowercase())
+            .filter(|domain| !domain.is_empty());
+        mail_from_domain.or_else(|| self.helo.as_ref().map(|helo| helo.to_lowercase()))
+    }
+
+    /// Returns the first header field with this name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim())
+    }
+
+    /// Reassembles the message in the RFC5322 format, with CRLF line endings
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut raw = vec![];
+        for (name, value) in &self.headers {
+            raw.extend_from_slice(name.as_bytes());
+            raw.push(b':');
+            push_crlf(&mut raw, value.as_bytes());
+            raw.extend_from_slice(b"\r\n");
+        }
+        raw.extend_from_slice(b"\r\n");
+        push_crlf(&mut raw, &self.body);
+        raw
+    }
+
+    fn reset_transaction(&mut self) {
+        self.mail_from = None;
+        self.headers.clear();
+        self.body.clear();
+    }
+}
+
+/// Appends the data, replacing bare LF line endings by CRLF
+fn push_crlf(raw: &mut Vec<u8>, data: &[u8]) {
+    for (i, &b) in data.iter().enumerate() {
+        if b == b'\n' && (i == 0 || data[i - 1] != b'\r') {
+            raw.push(b'\r');
+        }
+        raw.push(b);
+    }
+}
+
+/// Action requested from the MTA
+#[derive(Debug, PartialEq, Clone)]
+pub enum MilterAction {
+    Accept,
+    /// Holds the message, with the reason
+    Quarantine(String),
+    /// Rejects the message with the SMTP reply
+    Reject(String),
+}
+
+/// Outcome of the evaluation of a message
+#[derive(Debug, PartialEq, Clone)]
+pub struct Verdict {
+    pub action: MilterAction,
+    /// Value of the `Authentication-Results` header to add
+    pub authentication_results: String,
+}
+
+/// Milter applying the DMARC policies
+///
+/// DKIM, SPF and DMARC are verified by `DmarcVerifier::verify_message`, the
+/// SPF result comes from the verifier's `spf_source`.
+pub struct DmarcMilter {
+    /// Verifier used for the verification of the messages
+    pub verifier: Arc<DmarcVerifier>,
+    /// Identifier of the `Authentication-Results` header, usually the host
+    /// name of the MTA
+    pub authserv_id: String,
+    /// Quarantine or reject the messages failing DMARC. When disabled, only
+    /// the `Authentication-Results` header is added.
+    pub enforce: bool,
+    /// Maximum size of the body kept in memory, larger messages are rejected
+    pub max_message_size: usize,
+    pub logger: slog::Logger,
+}
+
+impl DmarcMilter {
+    /// Creates a milter enforcing the policies
+    pub fn new(verifier: impl Into<Arc<DmarcVerifier>>, authserv_id: &str) -> Self {
+        Self {
+            verifier: verifier.into(),
+            authserv_id: authserv_id.to_owned(),
+            enforce: true,
+            max_message_size: 64 * 1024 * 1024,
+            logger: slog::Logger::root(slog::Discard, slog::o!()),
+        }
+    }
+
+    /// Evaluates DMARC for the message
+    ///
+    /// Messages without a valid From header and DNS failures are accepted,
+    /// with a `permerror` or `temperror` result.
+    pub async fn evaluate(&self, message: &Message) -> Verdict {
+        let raw = message.to_bytes();
+        let res = self
+            .verifier
+            .verify_message(
+                &self.logger,
+                &raw,
+                message.client_ip,
+                message.helo.as_deref().unwrap_or_default(),
+                message.mail_from.as_deref().unwrap_or_default(),
+            )
+            .await;
+        let verdict = match res {
+            Ok(verdict) => verdict,
+            Err(err) => {
+                slog::debug!(self.logger, "failed to verify the message: {}", err);
+                let method = match err {
+                    DMARCError::InvalidMessage(_) | DMARCError::InvalidFromHeader(_) => {
+                        "dmarc=permerror".to_owned()
+                    }
+                    _ => match extract_from_domains(&raw, &self.verifier.from_header_options) {
+                        Ok(from_domains) => {
+                            format!("dmarc=temperror header.from={}", from_domains[0])
+                        }
+                        Err(_) => "dmarc=temperror".to_owned(),
+                    },
+                };
+                return Verdict {
+                    action: MilterAction::Accept,
+                    authentication_results: format!("{}; {}", self.authserv_id, method),
+                };
+            }
+        };
+
+        let action = match verdict.disposition() {
+            _ if !self.enforce => MilterAction::Accept,
+            ReceiverAction::None => MilterAction::Accept,
+            ReceiverAction::Quarantine => {
+                MilterAction::Quarantine(format!("DMARC policy of {}", verdict.result_domain))
+            }
+            ReceiverAction::Reject => MilterAction::Reject(format!(
+                "550 5.7.1 Email rejected per DMARC policy for {}",
+                verdict.result_domain
+            )),
+        };
+        Verdict {
+            action,
+            authentication_results: verdict.authentication_results(&self.authserv_id),
+        }
+    }
+
+    /// Accepts connections from the MTA
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            let milter = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(err) = milter.handle_connection(stream).await {
+                    slog::warn!(
+                        milter.logger,
+                        "milter connection from {} failed: {}",
+                        addr,
+                        err
+                    );
+                }
+            });
+        }
+    }
+
+    /// Accepts connections from the MTA on a Unix domain socket
+    #[cfg(unix)]
+    pub async fn serve_unix(self: Arc<Self>, listener: tokio::net::UnixListener) -> io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let milter = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(err) = milter.handle_connection(stream).await {
+                    slog::warn!(milter.logger, "milter connection failed: {}", err);
+                }
+            });
+        }
+    }
+
+    /// Runs the milter protocol on a connection from the MTA, until it quits
+    pub async fn handle_connection<S>(&self, mut stream: S) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut session = Session::default();
+        while let Some((command, data)) = read_packet(&mut stream).await? {
+            match command {
+                CMD_OPTNEG => {
+                    let reply = session.negotiate(&data)?;
+                    write_packet(&mut stream, RESP_OPTNEG, &reply).await?;
+                }
+                CMD_MACRO => {}
+                CMD_CONNECT => {
+                    session.message = Message::default();
+                    parse_connect(&data, &mut session.message);
+                    write_packet(&mut stream, RESP_CONTINUE, &[]).await?;
+                }
+                CMD_HELO => {
+                    session.message.helo = split_strings(&data).into_iter().next();
+                    write_packet(&mut stream, RESP_CONTINUE, &[]).await?;
+                }
+                CMD_MAIL => {
+                    session.message.reset_transaction();
+                    session.message.mail_from = split_strings(&data)
+                        .into_iter()
+                        .next()
+                        .map(|sender| sender.trim_matches(|c| c == '<' || c == '>').to_owned());
+                    write_packet(&mut stream, RESP_CONTINUE, &[]).await?;
+                }
+                CMD_RCPT | CMD_DATA | CMD_UNKNOWN | CMD_EOH => {
+                    write_packet(&mut stream, RESP_CONTINUE, &[]).await?;
+                }
+                CMD_HEADER => {
+                    let mut strings = split_strings(&data).into_iter();
+                    let name = strings.next().unwrap_or_default();
+                    let mut value = strings.next().unwrap_or_default();
+                    if !session.leading_space {
+                        value.insert(0, ' ');
+                    }
+                    session.message.headers.push((name, value));
+                    write_packet(&mut stream, RESP_CONTINUE, &[]).await?;
+                }
+                CMD_BODY => {
+                    if session.message.body.len() + data.len() > self.max_message_size {
+                        let mut reply = vec![];
+                        push_string(&mut reply, "552 5.3.4 Message too big for the DMARC check");
+                        write_packet(&mut stream, RESP_REPLYCODE, &reply).await?;
+                        session.message.reset_transaction();
+                        continue;
+                    }
+                    session.message.body.extend_from_slice(&data);
+                    write_packet(&mut stream, RESP_CONTINUE, &[]).await?;
+                }
+                CMD_BODYEOB => {
+                    session.message.body.extend_from_slice(&data);
+                    let verdict = self.evaluate(&session.message).await;
+                    self.send_verdict(&mut stream, &session, &verdict).await?;
+                    session.message.reset_transaction();
+                }
+                CMD_ABORT => session.message.reset_transaction(),
+                // A new connection follows without option negotiation, so
+                // only the message is reset
+                CMD_QUIT_NC => session.message = Message::default(),
+                CMD_QUIT => break,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unknown milter command: {:?}", command as char),
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn send_verdict<S>(
+        &self,
+        stream: &mut S,
+        session: &Session,
+        verdict: &Verdict,
+    ) -> io::Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let value_prefix = if session.leading_space { " " } else { "" };
+
+        // Headers claiming to come from this server are forged, they are
+        // removed as recommended by
+        // https://datatracker.ietf.org/doc/html/rfc8601#section-5
+        if session.actions & SMFIF_CHGHDRS != 0 {
+            let forged = session
+                .message
+                .headers
+                .iter()
+                .filter(|(name, _)| name.eq_ignore_ascii_case(AUTHENTICATION_RESULTS))
+                .enumerate()
+                .filter(|(_, (_, value))| self.is_own_authentication_results(value))
+                .map(|(i, _)| i as u32 + 1)
+                .collect::<Vec<_>>();
+            for index in forged {
+                let mut data = index.to_be_bytes().to_vec();
+                push_string(&mut data, AUTHENTICATION_RESULTS);
+                push_string(&mut data, "");
+                write_packet(stream, RESP_CHGHEADER, &data).await?;
+            }
+        }
+
+        if session.actions & SMFIF_ADDHDRS != 0 {
+            let mut data = 0u32.to_be_bytes().to_vec();
+            push_string(&mut data, AUTHENTICATION_RESULTS);
+            push_string(
+                &mut data,
+                &format!("{}{}", value_prefix, verdict.authentication_results),
+            );
+            write_packet(stream, RESP_INSHEADER, &data).await?;
+        }
+
+        match &verdict.action {
+            MilterAction::Accept => write_packet(stream, RESP_CONTINUE, &[]).await,
+            MilterAction::Quarantine(reason) => {
+                if session.actions & SMFIF_QUARANTINE != 0 {
+                    let mut data = vec![];
+                    push_string(&mut data, reason);
+                    write_packet(stream, RESP_QUARANTINE, &data).await?;
+                }
+                write_packet(stream, RESP_CONTINUE, &[]).await
+            }
+            MilterAction::Reject(reply) => {
+                let mut data = vec![];
+                push_string(&mut data, reply);
+                write_packet(stream, RESP_REPLYCODE, &data).await
+            }
+        }
+    }
+
+    fn is_own_authentication_results(&self, value: &str) -> bool {
+        let authserv_id = value.trim_start().split([';', ' ']).next().unwrap_or("");
+        authserv_id.eq_ignore_ascii_case(&self.authserv_id)
+    }
+}
+
+/// State of a connection from the MTA
+#[derive(Default)]
+struct Session {
+    /// Negotiated actions
+    actions: u32,
+    /// Whether header values are sent and expected with their leading space
+    leading_space: bool,
+    message: Message,
+}
+
+impl Session {
+    /// Negotiates the version, actions and protocol steps, returning the
+    /// reply
+    fn negotiate(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let (version, actions, protocol) =
+            match (read_u32(data, 0), read_u32(data, 4), read_u32(data, 8)) {
+                (Some(version), Some(actions), Some(protocol)) if version >= 2 => {
+                    (version, actions, protocol)
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unsupported milter option negotiation",
+                    ))
+                }
+            };
+
+        self.actions = actions & (SMFIF_ADDHDRS | SMFIF_CHGHDRS | SMFIF_QUARANTINE);
+        let protocol =
+            protocol & (SMFIP_NORCPT | SMFIP_NOUNKNOWN | SMFIP_NODATA | SMFIP_HDR_LEADSPC);
+        self.leading_space = protocol & SMFIP_HDR_LEADSPC != 0;
+
+        let mut reply = version.min(VERSION).to_be_bytes().to_vec();
+        reply.extend_from_slice(&self.actions.to_be_bytes());
+        reply.extend_from_slice(&protocol.to_be_bytes());
+        Ok(reply)
+    }
+}
+
+/// Parses the connect command: host name, address family, port and address
+fn parse_connect(data: &[u8], message: &mut Message) {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    message.client_name = Some(String::from_utf8_lossy(&data[..end]).into_owned());
+
+    // Only IPv4 and IPv6 clients have an address
+    if let Some(b'4' | b'6') = data.get(end + 1) {
+        let address = split_strings(data.get(end + 4..).unwrap_or_default());
+        message.client_ip = address.first().and_then(|address| {
+            // Sendmail prefixes IPv6 addresses
+            address
+                .trim_start_matches("IPv6:")
+                .trim_matches(|c| c == '[' || c == ']')
+                .parse()
+                .ok()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::testing::TestResolver;
+
+    fn milter(record: &str) -> DmarcMilter {
+        let resolver = TestResolver::default()
+            .txt("_dmarc.example.com", record)
+            .build();
+        let mut verifier = DmarcVerifier::new(resolver);
+        verifier.spf_source = Arc::new(crate::ReceivedSpfHeader);
+        DmarcMilter::new(verifier, "mx.local")
+    }
+
+    fn message(spf: &str) -> Message {
+        Message {
+            client_ip: Some("192.0.2.1".parse().unwrap()),
+            client_name: Some("mail.example.com".to_owned()),
+            helo: Some("mail.example.com".to_owned()),
+            mail_from: Some("bounce@example.com".to_owned()),
+            headers: vec![
+                ("Received-SPF".to_owned(), format!(" {} (mx.local)", spf)),
+                ("From".to_owned(), " Alice <alice@example.com>".to_owned()),
+                ("Subject".to_owned(), " Hello".to_owned()),
+            ],
+            body: b"Hello\n".to_vec(),
+        }
+    }
+
+    /// Fake MTA sending the message, returns the milter's responses to the
+    /// end of body
+    async fn send(milter: &DmarcMilter, message: &Message) -> Vec<(u8, Vec<u8>)> {
+        send_all(milter, std::slice::from_ref(message))
+            .await
+            .pop()
+            .unwrap()
+    }
+
+    /// Fake MTA sending the messages on the same connection, separated by
+    /// QUIT_NC, returns the milter's responses to each end of body
+    async fn send_all(milter: &DmarcMilter, messages: &[Message]) -> Vec<Vec<(u8, Vec<u8>)>> {
+        let (mut mta, stream) = tokio::io::duplex(4096);
+
+        let client = async move {
+            let mut optneg = VERSION.to_be_bytes().to_vec();
+            optneg.extend_from_slice(&0x1ffu32.to_be_bytes());
+            optneg.extend_from_slice(&0x1f_ffffu32.to_be_bytes());
+            write_packet(&mut mta, CMD_OPTNEG, &optneg).await.unwrap();
+            let (command, reply) = read_packet(&mut mta).await.unwrap().unwrap();
+            assert_eq!(command, RESP_OPTNEG);
+            assert_eq!(read_u32(&reply, 4), Some(0x31));
+            assert_eq!(read_u32(&reply, 8), Some(0x10_0308));
+
+            let mut all_responses = vec![];
+            for (i, message) in messages.iter().enumerate() {
+                if i > 0 {
+                    write_packet(&mut mta, CMD_QUIT_NC, &[]).await.unwrap();
+                }
+                all_responses.push(send_message(&mut mta, message).await);
+            }
+            write_packet(&mut mta, CMD_QUIT, &[]).await.unwrap();
+            all_responses
+        };
+
+        let (res, responses) = tokio::join!(milter.handle_connection(stream), client);
+        res.unwrap();
+        responses
+    }
+
+    /// Sends a transaction, returns the milter's responses to the end of body
+    async fn send_message(
+        mta: &mut tokio::io::DuplexStream,
+        message: &Message,
+    ) -> Vec<(u8, Vec<u8>)> {
+        let mut connect = vec![];
+        push_string(&mut connect, "mail.example.com");
+        connect.push(b'4');
+        connect.extend_from_slice(&25u16.to_be_bytes());
+        push_string(&mut connect, "192.0.2.1");
+
+        let mut packets = vec![
+            (CMD_MACRO, b"Cj\0mx.local\0".to_vec()),
+            (CMD_CONNECT, connect),
+            (CMD_HELO, b"mail.example.com\0".to_vec()),
+            (
+                CMD_MAIL,
+                format!("<{}>\0", message.mail_from.as_ref().unwrap()).into(),
+            ),
+            (CMD_RCPT, b"<bob@example.net>\0".to_vec()),
+        ];
+        for (name, value) in &message.headers {
+            let mut data = vec![];
+            push_string(&mut data, name);
+            push_string(&mut data, value);
+            packets.push((CMD_HEADER, data));
+        }
+        packets.push((CMD_EOH, vec![]));
+        packets.push((CMD_BODY, message.body.clone()));
+        for (command, data) in packets {
+            write_packet(mta, command, &data).await.unwrap();
+            if command != CMD_MACRO {
+                let (reply, _) = read_packet(mta).await.unwrap().unwrap();
+                assert_eq!(reply, RESP_CONTINUE);
+            }
+        }
+
+        write_packet(mta, CMD_BODYEOB, &[]).await.unwrap();
+        let mut responses = vec![];
+        loop {
+            let (command, data) = read_packet(mta).await.unwrap().unwrap();
+            responses.push((command, data));
+            if command == RESP_CONTINUE || command == RESP_REPLYCODE {
+                break;
+            }
+        }
+        responses
+    }
+
+    #[tokio::test]
+    async fn test_milter_reject() {
+        let responses = send(&milter("v=DMARC1; p=reject"), &message("fail")).await;
+        assert_eq!(responses.len(), 2);
+
+        let (command, data) = &responses[0];
+        assert_eq!(*command, RESP_INSHEADER);
+        assert_eq!(read_u32(data, 0), Some(0));
+        assert_eq!(
+            split_strings(&data[4..]),
+            vec![
+                "Authentication-Results",
+                " mx.local; dkim=neutral header.d=example.com; \
+                 spf=fail smtp.mailfrom=bounce@example.com; \
+                 dmarc=fail (p=reject dis=reject) header.from=example.com"
+            ]
+        );
+
+        let (command, data) = &responses[1];
+        assert_eq!(*command, RESP_REPLYCODE);
+        assert!(split_strings(data)[0].starts_with("550 5.7.1 "));
+    }
+
+    #[tokio::test]
+    async fn test_milter_pass() {
+        let mut message = message("pass");
+        message.headers.push((
+            "Authentication-Results".to_owned(),
+            " mx.local; dmarc=pass".to_owned(),
+        ));
+        let responses = send(&milter("v=DMARC1; p=reject"), &message).await;
+        let commands: Vec<u8> = responses.iter().map(|(command, _)| *command).collect();
+        assert_eq!(
+            commands,
+            vec![RESP_CHGHEADER, RESP_INSHEADER, RESP_CONTINUE]
+        );
+
+        // The forged header is removed
+        assert_eq!(read_u32(&responses[0].1, 0), Some(1));
+        assert!(split_strings(&responses[1].1[4..])[1]
+            .ends_with("dmarc=pass (p=reject dis=none) header.from=example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_milter_quit_nc() {
+        // The negotiated options still apply to the next connections
+        let milter = milter("v=DMARC1; p=quarantine");
+        let messages = [message("fail"), message("softfail")];
+        let responses = send_all(&milter, &messages).await;
+        assert_eq!(responses.len(), 2);
+        for responses in responses {
+            let commands: Vec<u8> = responses.iter().map(|(command, _)| *command).collect();
+            assert_eq!(
+                commands,
+                vec![RESP_INSHEADER, RESP_QUARANTINE, RESP_CONTINUE]
+            );
+            // The header value still has its leading space
+            assert!(split_strings(&responses[0].1[4..])[1].starts_with(" mx.local; "));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_milter_message_too_big() {
+        let mut milter = milter("v=DMARC1; p=reject");
+        milter.max_message_size = 8;
+        let (mut mta, stream) = tokio::io::duplex(4096);
+
+        let client = async move {
+            write_packet(&mut mta, CMD_MAIL, b"<bounce@example.com>\0")
+                .await
+                .unwrap();
+            let (reply, _) = read_packet(&mut mta).await.unwrap().unwrap();
+            assert_eq!(reply, RESP_CONTINUE);
+
+            write_packet(&mut mta, CMD_BODY, b"Hello\n").await.unwrap();
+            let (reply, _) = read_packet(&mut mta).await.unwrap().unwrap();
+            assert_eq!(reply, RESP_CONTINUE);
+
+            write_packet(&mut mta, CMD_BODY, b"World\n").await.unwrap();
+            let (reply, data) = read_packet(&mut mta).await.unwrap().unwrap();
+            assert_eq!(reply, RESP_REPLYCODE);
+            assert!(split_strings(&data)[0].starts_with("552 5.3.4 "));
+            write_packet(&mut mta, CMD_QUIT, &[]).await.unwrap();
+        };
+
+        let (res, _) = tokio::join!(milter.handle_connection(stream), client);
+        res.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_evaluate() {
+        let mut milter = milter("v=DMARC1; p=quarantine");
+        let verdict = milter.evaluate(&message("softfail")).await;
+        assert_eq!(
+            verdict.action,
+            MilterAction::Quarantine("DMARC policy of example.com".to_owned())
+        );
+
+        milter.enforce = false;
+        let verdict = milter.evaluate(&message("softfail")).await;
+        assert_eq!(verdict.action, MilterAction::Accept);
+
+        let mut message = message("pass");
+        message.headers.retain(|(name, _)| name != "From");
+        let verdict = milter.evaluate(&message).await;
+        assert_eq!(verdict.action, MilterAction::Accept);
+        assert!(verdict.authentication_results.ends_with("dmarc=permerror"));
+    }
+
+    #[test]
+    fn test_message() {
+        let mut message = message("pass");
+        message
+            .headers
+            .push(("X-Folded".to_owned(), " a\n\tb".to_owned()));
+        message.body = b"a\nb\r\n".to_vec();
+        assert_eq!(
+            String::from_utf8(message.to_bytes()).unwrap(),
+            "Received-SPF: pass (mx.local)\r\nFrom: Alice <alice@example.com>\r\n\
+             Subject: Hello\r\nX-Folded: a\r\n\tb\r\n\r\na\r\nb\r\n"
+        );
+        assert_eq!(message.spf_domain(), Some("example.com".to_owned()));
+        message.mail_from = Some(String::new());
+        assert_eq!(message.spf_domain(), Some("mail.example.com".to_owned()));
+
+        let mut message = Message::default();
+        parse_connect(b"host\0\x36\0\x19IPv6:2001:db8::1\0", &mut message);
+        assert_eq!(message.client_name.as_deref(), Some("host"));
+        assert_eq!(message.client_ip, Some("2001:db8::1".parse().unwrap()));
+        parse_connect(b"localhost\0L\0\0/tmp/sock\0", &mut message);
+        assert_eq!(message.client_ip, Some("2001:db8::1".parse().unwrap()));
+    }
+}
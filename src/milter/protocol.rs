@@ -0,0 +1,99 @@
+/// Milter wire protocol, as implemented by libmilter (version 6)
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub(crate) const VERSION: u32 = 6;
+
+/// Maximum size of a packet, libmilter's body chunks are at most 64KiB
+const MAX_PACKET_LEN: usize = 1024 * 1024;
+
+// Commands sent by the MTA
+pub(crate) const CMD_ABORT: u8 = b'A';
+pub(crate) const CMD_BODY: u8 = b'B';
+pub(crate) const CMD_CONNECT: u8 = b'C';
+pub(crate) const CMD_DATA: u8 = b'T';
+pub(crate) const CMD_MACRO: u8 = b'D';
+pub(crate) const CMD_BODYEOB: u8 = b'E';
+pub(crate) const CMD_EOH: u8 = b'N';
+pub(crate) const CMD_HELO: u8 = b'H';
+pub(crate) const CMD_QUIT_NC: u8 = b'K';
+pub(crate) const CMD_HEADER: u8 = b'L';
+pub(crate) const CMD_MAIL: u8 = b'M';
+pub(crate) const CMD_OPTNEG: u8 = b'O';
+pub(crate) const CMD_QUIT: u8 = b'Q';
+pub(crate) const CMD_RCPT: u8 = b'R';
+pub(crate) const CMD_UNKNOWN: u8 = b'U';
+
+// Responses sent by the milter
+pub(crate) const RESP_CHGHEADER: u8 = b'm';
+pub(crate) const RESP_CONTINUE: u8 = b'c';
+pub(crate) const RESP_INSHEADER: u8 = b'i';
+pub(crate) const RESP_OPTNEG: u8 = b'O';
+pub(crate) const RESP_QUARANTINE: u8 = b'q';
+pub(crate) const RESP_REPLYCODE: u8 = b'y';
+
+// Actions the milter can perform
+pub(crate) const SMFIF_ADDHDRS: u32 = 0x01;
+pub(crate) const SMFIF_CHGHDRS: u32 = 0x10;
+pub(crate) const SMFIF_QUARANTINE: u32 = 0x20;
+
+// Protocol steps the MTA can skip
+pub(crate) const SMFIP_NORCPT: u32 = 0x08;
+pub(crate) const SMFIP_NOUNKNOWN: u32 = 0x100;
+pub(crate) const SMFIP_NODATA: u32 = 0x200;
+/// Header values are sent with their leading space
+pub(crate) const SMFIP_HDR_LEADSPC: u32 = 0x10_0000;
+
+/// Reads a packet, `None` when the connection was closed
+pub(crate) async fn read_packet<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let len = match reader.read_u32().await {
+        Ok(len) => len as usize,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    if len == 0 || len > MAX_PACKET_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid milter packet length: {}", len),
+        ));
+    }
+
+    let command = reader.read_u8().await?;
+    let mut data = vec![0; len - 1];
+    reader.read_exact(&mut data).await?;
+    Ok(Some((command, data)))
+}
+
+pub(crate) async fn write_packet<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    command: u8,
+    data: &[u8],
+) -> io::Result<()> {
+    let mut packet = Vec::with_capacity(data.len() + 5);
+    packet.extend_from_slice(&(data.len() as u32 + 1).to_be_bytes());
+    packet.push(command);
+    packet.extend_from_slice(data);
+    writer.write_all(&packet).await?;
+    writer.flush().await
+}
+
+/// Splits NUL-terminated strings
+pub(crate) fn split_strings(data: &[u8]) -> Vec<String> {
+    let data = data.strip_suffix(&[0]).unwrap_or(data);
+    data.split(|&b| b == 0)
+        .map(|s| String::from_utf8_lossy(s).into_owned())
+        .collect()
+}
+
+/// Appends a NUL-terminated string
+pub(crate) fn push_string(data: &mut Vec<u8>, s: &str) {
+    data.extend_from_slice(s.as_bytes());
+    data.push(0);
+}
+
+pub(crate) fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
@@ -1,7 +1,16 @@
-use crate::policy::{Alignement, ReceiverAction};
+use crate::policy::{Alignement, PublicSuffixDomain, ReceiverAction, ReportUri};
 use crate::DMARCError;
 
-pub use cfdkim::Tag;
+/// Tag of a DMARC record
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Tag {
+    pub name: String,
+    /// Value of the tag with spaces removed
+    pub value: String,
+    /// Value of the tag as seen in the record
+    pub raw_value: String,
+}
 
 pub(crate) fn parse(input: &str) -> Result<Vec<Tag>, DMARCError> {
     // DMARC records follow the extensible "tag-value" syntax for DNS-based key
@@ -9,7 +18,26 @@ pub(crate) fn parse(input: &str) -> Result<Vec<Tag>, DMARCError> {
     let (_, tags) = cfdkim::parse_tag_list(input)
         .map_err(|err| DMARCError::PolicyParseError(err.to_string()))?;
 
-    Ok(tags)
+    Ok(tags
+        .into_iter()
+        .map(|tag| Tag {
+            name: tag.name,
+            value: tag.value,
+            raw_value: tag.raw_value,
+        })
+        .collect())
+}
+
+/// Checks if the record's first tag is `v=DMARC1`, as required by
+/// https://datatracker.ietf.org/doc/html/rfc7489#section-6.6.3
+pub(crate) fn is_dmarc_record(record: &str) -> bool {
+    let check = || -> Option<bool> {
+        let rest = record.trim_start().strip_prefix('v')?;
+        let rest = rest.trim_start().strip_prefix('=')?;
+        let rest = rest.trim_start().strip_prefix("DMARC1")?.trim_start();
+        Some(rest.is_empty() || rest.starts_with(';'))
+    };
+    check().unwrap_or(false)
 }
 
 pub(crate) fn parse_alignement_mode(input: &str) -> Alignement {
@@ -46,10 +74,120 @@ pub(crate) fn parse_percentage(input: &str) -> usize {
     }
 }
 
+pub(crate) fn parse_psd_flag(input: &str) -> PublicSuffixDomain {
+    match input {
+        "y" => PublicSuffixDomain::Yes,
+        "n" => PublicSuffixDomain::No,
+        _ => PublicSuffixDomain::default(),
+    }
+}
+
+pub(crate) fn parse_testing_mode(input: &str) -> bool {
+    input == "y"
+}
+
+pub(crate) fn parse_failure_options(input: &str) -> Option<String> {
+    let valid = input
+        .split(':')
+        .all(|option| matches!(option.trim(), "0" | "1" | "d" | "s"));
+    if valid {
+        Some(
+            input
+                .split(':')
+                .map(|option| option.trim())
+                .collect::<Vec<_>>()
+                .join(":"),
+        )
+    } else {
+        None
+    }
+}
+
+/// Parses the comma separated list of reporting URIs of the `rua` and `ruf`
+/// tags, see https://datatracker.ietf.org/doc/html/rfc7489#section-6.4
+///
+/// Invalid URIs are ignored.
+pub(crate) fn parse_report_uris(input: &str) -> Vec<ReportUri> {
+    input
+        .split(',')
+        .filter_map(|uri| parse_report_uri(uri.trim()))
+        .collect()
+}
+
+pub(crate) fn parse_report_uri(input: &str) -> Option<ReportUri> {
+    let (uri, max_size) = match input.rsplit_once('!') {
+        Some((uri, size)) => (uri, Some(parse_size(size)?)),
+        None => (input, None),
+    };
+
+    // The URI must at least have a scheme and a non-empty remainder
+    let (scheme, rest) = uri.split_once(':')?;
+    let mut chars = scheme.chars();
+    let valid_scheme = chars.next()?.is_ascii_alphabetic()
+        && chars.all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+    if !valid_scheme || rest.is_empty() || rest.contains(char::is_whitespace) {
+        return None;
+    }
+
+    Some(ReportUri {
+        uri: uri.to_owned(),
+        max_size,
+    })
+}
+
+fn parse_size(input: &str) -> Option<u64> {
+    let (digits, multiplier) = match input.chars().last()? {
+        'k' => (&input[..input.len() - 1], 1 << 10),
+        'm' => (&input[..input.len() - 1], 1 << 20),
+        'g' => (&input[..input.len() - 1], 1 << 30),
+        't' => (&input[..input.len() - 1], 1 << 40),
+        _ => (input, 1),
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_is_dmarc_record() {
+        assert!(is_dmarc_record("v=DMARC1; p=none"));
+        assert!(is_dmarc_record("v=DMARC1"));
+        assert!(is_dmarc_record(" v = DMARC1;p=none"));
+        assert!(!is_dmarc_record("v=DMARC1p=none"));
+        assert!(!is_dmarc_record("v=DMARC2; p=none"));
+        assert!(!is_dmarc_record("v=dmarc1; p=none"));
+        assert!(!is_dmarc_record("p=none; v=DMARC1"));
+        assert!(!is_dmarc_record("v=spf1 -all"));
+    }
+
+    #[test]
+    fn test_parse_report_uris() {
+        assert_eq!(
+            parse_report_uris("mailto:a@example.com, mailto:b@example.com!10m,hein,mailto:c!x"),
+            vec![
+                ReportUri {
+                    uri: "mailto:a@example.com".to_owned(),
+                    max_size: None
+                },
+                ReportUri {
+                    uri: "mailto:b@example.com".to_owned(),
+                    max_size: Some(10 * 1024 * 1024)
+                },
+            ]
+        );
+        assert_eq!(
+            parse_report_uris("https://example.com/r!50")[0].max_size,
+            Some(50)
+        );
+        assert!(parse_report_uris("").is_empty());
+        assert!(parse_report_uris("1mailto:a@example.com").is_empty());
+    }
+
     #[test]
     fn test_parse() {
         assert_eq!(
@@ -3,15 +3,20 @@ use rand::distributions::Distribution;
 use slog::debug;
 use std::default::Default;
 
-use crate::{dns, DMARCResult, PolicyContext};
+use crate::trace::{AlignmentTrace, PolicyTrace, SamplingTrace};
+use crate::{psl, DMARCResult, OrgDomainResolver, PolicyContext, Tag};
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Alignement {
+    #[cfg_attr(feature = "serde", serde(rename = "r"))]
     Relaxed,
+    #[cfg_attr(feature = "serde", serde(rename = "s"))]
     Strict,
 }
 // Since deriving `Default` on enums is experimental we'll need to implement
 // it ourselves for the time being
+#[allow(clippy::derivable_impls)]
 impl Default for Alignement {
     fn default() -> Self {
         Self::Relaxed
@@ -19,6 +24,8 @@ impl Default for Alignement {
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum ReceiverAction {
     None,
     Quarantine,
@@ -32,9 +39,70 @@ impl ReceiverAction {
             Self::Reject => "reject",
         }
     }
+
+    /// Returns the next less restrictive action, used by the testing mode
+    /// (`t=y`) as specified in
+    /// https://datatracker.ietf.org/doc/html/draft-ietf-dmarc-dmarcbis#section-5.3
+    pub fn downgrade(&self) -> Self {
+        match self {
+            Self::None | Self::Quarantine => Self::None,
+            Self::Reject => Self::Quarantine,
+        }
+    }
+}
+
+/// Value of the DMARCbis `psd` tag
+#[derive(Debug, PartialEq, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PublicSuffixDomain {
+    /// The domain is a Public Suffix Domain (`psd=y`)
+    #[cfg_attr(feature = "serde", serde(rename = "y"))]
+    Yes,
+    /// The domain is an Organizational Domain (`psd=n`)
+    #[cfg_attr(feature = "serde", serde(rename = "n"))]
+    No,
+    /// Unknown (`psd=u` or no tag)
+    #[default]
+    #[cfg_attr(feature = "serde", serde(rename = "u"))]
+    Unknown,
+}
+
+/// Reporting URI of the `rua` and `ruf` tags, as specified in
+/// https://datatracker.ietf.org/doc/html/rfc7489#section-6.2
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ReportUri {
+    pub uri: String,
+    /// Maximum size of the reports in bytes (`!` suffix)
+    pub max_size: Option<u64>,
+}
+
+/// Decides if a DMARC policy applies to a message based on its `pct` tag
+pub trait Sampler: Send + Sync {
+    fn should_apply(&self, pct: usize) -> bool;
+}
+
+/// Samples messages randomly, as specified in
+/// https://datatracker.ietf.org/doc/html/rfc7489#section-6.6.4
+pub struct RandomSampler;
+
+impl Sampler for RandomSampler {
+    fn should_apply(&self, pct: usize) -> bool {
+        let d = match Bernoulli::new(pct as f64 / 100.0) {
+            Ok(d) => d,
+            Err(_) => {
+                // an invalid probability throws an error, it's unlikely to happen
+                // given that we validate the value before.
+                // Return true like rcpt = 100.
+                return true;
+            }
+        };
+        d.sample(&mut rand::thread_rng())
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 /// DMARC policy
 pub struct Policy {
     /// DKIM Identifier Alignment mode
@@ -43,8 +111,37 @@ pub struct Policy {
     pub aspf: Alignement,
     /// Requested Mail Receiver policy (includes subdomain)
     pub action: ReceiverAction,
+    /// Requested Mail Receiver policy (`p` tag), as published. Unlike
+    /// `action` it isn't replaced by `sp` for policies found at the
+    /// Organizational Domain. Repaired policies have `none`.
+    pub p: ReceiverAction,
+    /// Requested Mail Receiver policy for subdomains (`sp` tag), as published
+    pub sp: Option<ReceiverAction>,
     /// Percentage of messages to which the DMARC policy is to be applied
     pub pct: usize,
+    /// Requested Mail Receiver policy for non-existent subdomains (`np` tag)
+    pub np: Option<ReceiverAction>,
+    /// Whether the domain is a Public Suffix Domain (`psd` tag)
+    pub psd: PublicSuffixDomain,
+    /// Testing mode (`t=y`), the dispositions are downgraded
+    pub testing: bool,
+    /// Addresses to which aggregate feedback is to be sent (`rua` tag)
+    pub rua: Vec<ReportUri>,
+    /// Addresses to which failure reports are to be sent (`ruf` tag)
+    pub ruf: Vec<ReportUri>,
+    /// Failure reporting options (`fo` tag)
+    pub fo: String,
+    /// Interval in seconds between aggregate reports (`ri` tag)
+    pub ri: u32,
+    /// Whether the record had an invalid or missing `p` tag, or an invalid
+    /// `sp` tag, and was treated as `p=none` because of its valid `rua` tag
+    pub repaired: bool,
+    /// Record the policy was parsed from, empty if it wasn't parsed
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub record: String,
+    /// All the tags of the record in order, including the unknown ones
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub tags: Vec<Tag>,
 }
 
 impl Policy {
@@ -55,30 +152,86 @@ impl Policy {
             adkim: Alignement::Relaxed,
             aspf: Alignement::Relaxed,
             pct: 100,
+            p: action.clone(),
             action,
+            sp: None,
+            np: None,
+            psd: PublicSuffixDomain::Unknown,
+            testing: false,
+            rua: vec![],
+            ruf: vec![],
+            fo: "0".to_owned(),
+            ri: 86400,
+            repaired: false,
+            record: String::new(),
+            tags: vec![],
         }
     }
 
+    /// Whether both policies request the same handling, ignoring the `record`
+    /// and `tags` they were parsed from
+    pub fn is_equivalent(&self, other: &Policy) -> bool {
+        let Policy {
+            adkim,
+            aspf,
+            action,
+            p,
+            sp,
+            pct,
+            np,
+            psd,
+            testing,
+            rua,
+            ruf,
+            fo,
+            ri,
+            repaired,
+            record: _,
+            tags: _,
+        } = self;
+        *adkim == other.adkim
+            && *aspf == other.aspf
+            && *action == other.action
+            && *p == other.p
+            && *sp == other.sp
+            && *pct == other.pct
+            && *np == other.np
+            && *psd == other.psd
+            && *testing == other.testing
+            && *rua == other.rua
+            && *ruf == other.ruf
+            && *fo == other.fo
+            && *ri == other.ri
+            && *repaired == other.repaired
+    }
+
+    /// Returns the tag of the record with the given name
+    pub fn tag(&self, name: &str) -> Option<&Tag> {
+        self.tags.iter().find(|tag| tag.name == name)
+    }
+
     /// Based on the `pct` tag, determine if the DMARC policy should be applied
     pub fn should_apply(&self) -> bool {
-        let d = match Bernoulli::new(self.pct as f64 / 100.0) {
-            Ok(d) => d,
-            Err(_) => {
-                // an invalid probability throws an error, it's unlikely to happen
-                // given that we validate the value before.
-                // Return true like rcpt = 100.
-                return true;
-            }
-        };
-        d.sample(&mut rand::thread_rng())
+        RandomSampler.should_apply(self.pct)
     }
 
     // https://datatracker.ietf.org/doc/html/rfc7489#section-3.1
     pub fn check_spf_alignment(&self, from_domain: &str, spf_domain: &str) -> bool {
+        self.check_spf_alignment_with(&psl::BUILTIN, from_domain, spf_domain)
+    }
+
+    /// Same as `check_spf_alignment` but computes Organizational Domains
+    /// with the given resolver
+    pub fn check_spf_alignment_with(
+        &self,
+        org_domain_resolver: &OrgDomainResolver,
+        from_domain: &str,
+        spf_domain: &str,
+    ) -> bool {
         match self.aspf {
             Alignement::Relaxed => {
-                let root_from = dns::get_root_domain_name(from_domain);
-                let root_used_domain = dns::get_root_domain_name(spf_domain);
+                let root_from = org_domain_resolver.organizational_domain(from_domain);
+                let root_used_domain = org_domain_resolver.organizational_domain(spf_domain);
 
                 if root_from == root_used_domain {
                     return true;
@@ -97,11 +250,23 @@ impl Policy {
         &self,
         from_domain: &str,
         dkim_result: &cfdkim::DKIMResult,
+    ) -> bool {
+        self.check_dkim_alignment_with(&psl::BUILTIN, from_domain, dkim_result)
+    }
+
+    /// Same as `check_dkim_alignment` but computes Organizational Domains
+    /// with the given resolver
+    pub fn check_dkim_alignment_with(
+        &self,
+        org_domain_resolver: &OrgDomainResolver,
+        from_domain: &str,
+        dkim_result: &cfdkim::DKIMResult,
     ) -> bool {
         match self.adkim {
             Alignement::Relaxed => {
-                let root_from = dns::get_root_domain_name(from_domain);
-                let root_used_domain = dns::get_root_domain_name(&dkim_result.domain_used());
+                let root_from = org_domain_resolver.organizational_domain(from_domain);
+                let root_used_domain =
+                    org_domain_resolver.organizational_domain(&dkim_result.domain_used());
 
                 if root_from == root_used_domain {
                     return true;
@@ -124,39 +289,125 @@ impl Policy {
     ///
     /// Checks authentication mechanisms result
     /// https://datatracker.ietf.org/doc/html/rfc7489#section-4.2
+    ///
+    /// The policy of the returned result carries the action effectively
+    /// requested for this message, after the `np` and `t` tags were taken into
+    /// account.
+    ///
+    /// The `pct` tag only applies to failures: aligned messages pass and
+    /// failing messages that aren't sampled are `neutral`.
     pub fn apply(&self, ctx: &PolicyContext) -> DMARCResult {
-        if !self.should_apply() {
+        self.apply_with_trace(ctx).0
+    }
+
+    /// Same as `apply` but also returns the trace of the decision
+    pub fn apply_with_trace(&self, ctx: &PolicyContext) -> (DMARCResult, PolicyTrace) {
+        let policy = self.effective_policy(ctx);
+        let should_apply = match ctx.sampler {
+            Some(sampler) => sampler.should_apply(self.pct),
+            None => self.should_apply(),
+        };
+        let mut trace = PolicyTrace {
+            action: policy.action.clone(),
+            np_applied: ctx.from_domain_exists == Some(false) && self.np.is_some(),
+            testing: self.testing,
+            sampling: SamplingTrace {
+                pct: self.pct,
+                applied: should_apply,
+            },
+            dkim: None,
+            spf: None,
+            result: String::new(),
+            disposition: ReceiverAction::None,
+        };
+
+        // The alignment is checked before the sampling so that the trace
+        // explains the result, the `pct` tag only applies to failures
+        let result = if self.check_authentication(ctx, &mut trace) {
+            DMARCResult::pass(policy)
+        } else if should_apply {
+            DMARCResult::fail(policy)
+        } else {
             debug!(ctx.logger, "should not apply DMARC policy");
-            return DMARCResult::neutral(self.clone());
-        }
+            DMARCResult::neutral(policy)
+        };
+
+        trace.result = result.to_str().to_owned();
+        trace.disposition = result.disposition();
+        (result, trace)
+    }
 
+    /// Checks if an aligned authentication mechanism passes
+    // https://datatracker.ietf.org/doc/html/rfc7489#section-4.2
+    fn check_authentication(&self, ctx: &PolicyContext, trace: &mut PolicyTrace) -> bool {
         // comparison should be done in a case-insensitive manner
         // as per https://datatracker.ietf.org/doc/html/rfc7489#section-3.1
         let from_domain = ctx.from_domain.to_lowercase();
         let spf_domain = ctx.spf_result.domain_used.to_lowercase();
+        let org_domain_resolver = ctx.org_domain_resolver.unwrap_or(&psl::BUILTIN);
+
+        let dkim = AlignmentTrace {
+            mode: self.adkim.clone(),
+            from_domain: from_domain.clone(),
+            from_org_domain: org_domain_resolver.organizational_domain(&from_domain),
+            domain: ctx.dkim_result.domain_used(),
+            org_domain: org_domain_resolver.organizational_domain(&ctx.dkim_result.domain_used()),
+            aligned: self.check_dkim_alignment_with(
+                org_domain_resolver,
+                &from_domain,
+                &ctx.dkim_result,
+            ),
+            result: ctx.dkim_result.summary().to_owned(),
+        };
+        let spf = AlignmentTrace {
+            mode: self.aspf.clone(),
+            from_domain: from_domain.clone(),
+            from_org_domain: dkim.from_org_domain.clone(),
+            domain: spf_domain.clone(),
+            org_domain: org_domain_resolver.organizational_domain(&spf_domain),
+            aligned: self.check_spf_alignment_with(org_domain_resolver, &from_domain, &spf_domain),
+            result: ctx.spf_result.value.clone(),
+        };
+        let dkim_pass = dkim.aligned && dkim.result == "pass";
+        let spf_pass = spf.aligned && spf.result == "pass";
 
         // If DKIM is aligned, check its result. If pass, DMARC passes
-        if self.check_dkim_alignment(&from_domain, &ctx.dkim_result) {
-            let res = ctx.dkim_result.summary();
-            if res == "pass" {
-                return DMARCResult::pass(self.clone());
-            }
-
-            debug!(ctx.logger, "dkim aligned but result {}", res);
+        if dkim.aligned && !dkim_pass {
+            debug!(ctx.logger, "dkim aligned but result {}", dkim.result);
         }
-
         // If PSF is aligned, check its result. If pass, DMARC passes
-        if self.check_spf_alignment(&from_domain, &spf_domain) {
-            let res = &ctx.spf_result.value;
-            if res == "pass" {
-                return DMARCResult::pass(self.clone());
+        if spf.aligned && !spf_pass {
+            debug!(ctx.logger, "spf aligned but result {}", spf.result);
+        }
+        trace.dkim = Some(dkim);
+        trace.spf = Some(spf);
+
+        // If no authentication mechanisms were aligned and passes, DMARC fails
+        dkim_pass || spf_pass
+    }
+
+    /// Returns a copy of the policy with the action requested for the message
+    fn effective_policy(&self, ctx: &PolicyContext) -> Self {
+        let mut policy = self.clone();
+
+        // https://datatracker.ietf.org/doc/html/rfc9091#section-4.1
+        if ctx.from_domain_exists == Some(false) {
+            if let Some(np) = &self.np {
+                debug!(
+                    ctx.logger,
+                    "from domain doesn't exist, using np={}",
+                    np.to_str()
+                );
+                policy.action = np.clone();
             }
+        }
 
-            debug!(ctx.logger, "spf aligned but result {}", res);
+        if self.testing {
+            debug!(ctx.logger, "policy in testing mode");
+            policy.action = policy.action.downgrade();
         }
 
-        // No authentication mechanisms were aligned and passes, DMARC fails
-        DMARCResult::fail(self.clone())
+        policy
     }
 }
 
@@ -186,87 +437,166 @@ mod tests {
 
         // SPF & DKIM pass
         {
-            let ctx = PolicyContext {
+            let ctx = PolicyContext::new(
                 from_domain,
-                logger: &logger,
-                dkim_result: cfdkim::DKIMResult::pass(
-                    "a.com".to_owned(),
-                    Type::Simple,
-                    Type::Simple,
-                ),
-                spf_result: SPFResult {
+                cfdkim::DKIMResult::pass("a.com".to_owned(), Type::Simple, Type::Simple),
+                SPFResult {
                     domain_used: "a.com".to_string(),
                     value: "pass".to_string(),
                 },
-            };
+                &logger,
+            );
             assert_eq!(policy.apply(&ctx).to_str(), "pass");
         }
 
         // SPF & DKIM pass but not aligned
         {
-            let ctx = PolicyContext {
+            let ctx = PolicyContext::new(
                 from_domain,
-                logger: &logger,
-                dkim_result: cfdkim::DKIMResult::pass(
-                    "b.com".to_owned(),
-                    Type::Simple,
-                    Type::Simple,
-                ),
-                spf_result: SPFResult {
+                cfdkim::DKIMResult::pass("b.com".to_owned(), Type::Simple, Type::Simple),
+                SPFResult {
                     domain_used: "b.com".to_string(),
                     value: "pass".to_string(),
                 },
-            };
+                &logger,
+            );
             assert_eq!(policy.apply(&ctx).to_str(), "fail");
         }
 
         // SPF pass
         {
-            let ctx = PolicyContext {
+            let ctx = PolicyContext::new(
                 from_domain,
-                logger: &logger,
-                dkim_result: cfdkim::DKIMResult::neutral("a.com".to_owned()),
-                spf_result: SPFResult {
+                cfdkim::DKIMResult::neutral("a.com".to_owned()),
+                SPFResult {
                     domain_used: "a.com".to_string(),
                     value: "pass".to_string(),
                 },
-            };
+                &logger,
+            );
             assert_eq!(policy.apply(&ctx).to_str(), "pass");
         }
 
         // DKIM pass
         {
-            let ctx = PolicyContext {
+            let ctx = PolicyContext::new(
                 from_domain,
-                logger: &logger,
-                dkim_result: cfdkim::DKIMResult::pass(
-                    "a.com".to_owned(),
-                    Type::Simple,
-                    Type::Simple,
-                ),
-                spf_result: SPFResult {
+                cfdkim::DKIMResult::pass("a.com".to_owned(), Type::Simple, Type::Simple),
+                SPFResult {
                     domain_used: "a.com".to_string(),
                     value: "fail".to_string(),
                 },
-            };
+                &logger,
+            );
             assert_eq!(policy.apply(&ctx).to_str(), "pass");
         }
 
         // non pass
         {
-            let ctx = PolicyContext {
+            let ctx = PolicyContext::new(
                 from_domain,
-                logger: &logger,
-                dkim_result: cfdkim::DKIMResult::neutral("a.com".to_owned()),
-                spf_result: SPFResult {
+                cfdkim::DKIMResult::neutral("a.com".to_owned()),
+                SPFResult {
                     domain_used: "a.com".to_string(),
                     value: "fail".to_string(),
                 },
-            };
+                &logger,
+            );
             assert_eq!(policy.apply(&ctx).to_str(), "fail");
         }
     }
 
+    #[test]
+    fn test_apply_np() {
+        let mut policy = Policy::new(ReceiverAction::Quarantine);
+        policy.np = Some(ReceiverAction::Reject);
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+
+        let ctx = PolicyContext::new(
+            "nx.a.com",
+            cfdkim::DKIMResult::neutral("a.com".to_owned()),
+            SPFResult {
+                domain_used: "b.com".to_string(),
+                value: "pass".to_string(),
+            },
+            &logger,
+        );
+        assert_eq!(policy.apply(&ctx).disposition(), ReceiverAction::Quarantine);
+
+        let ctx = ctx.with_from_domain_exists(false);
+        assert_eq!(policy.apply(&ctx).disposition(), ReceiverAction::Reject);
+
+        // Without np the policy's action is used
+        policy.np = None;
+        assert_eq!(policy.apply(&ctx).disposition(), ReceiverAction::Quarantine);
+    }
+
+    #[test]
+    fn test_apply_testing() {
+        let mut policy = Policy::new(ReceiverAction::Reject);
+        policy.testing = true;
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+
+        let ctx = PolicyContext::new(
+            "a.com",
+            cfdkim::DKIMResult::neutral("a.com".to_owned()),
+            SPFResult {
+                domain_used: "b.com".to_string(),
+                value: "pass".to_string(),
+            },
+            &logger,
+        );
+        let res = policy.apply(&ctx);
+        assert_eq!(res.to_str(), "fail");
+        assert_eq!(res.disposition(), ReceiverAction::Quarantine);
+        assert!(!res.should_reject());
+
+        policy.action = ReceiverAction::Quarantine;
+        assert_eq!(policy.apply(&ctx).disposition(), ReceiverAction::None);
+    }
+
+    #[test]
+    fn test_apply_with_trace() {
+        let mut policy = Policy::new(ReceiverAction::Reject);
+        policy.aspf = Alignement::Strict;
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+
+        let ctx = PolicyContext::new(
+            "a.com",
+            cfdkim::DKIMResult::neutral("mail.a.com".to_owned()),
+            SPFResult {
+                domain_used: "bounce.a.com".to_string(),
+                value: "pass".to_string(),
+            },
+            &logger,
+        );
+        let (res, trace) = policy.apply_with_trace(&ctx);
+        assert_eq!(res.to_str(), "fail");
+        assert_eq!(trace.result, "fail");
+        assert_eq!(trace.disposition, ReceiverAction::Reject);
+        assert!(trace.sampling.applied);
+
+        let dkim = trace.dkim.unwrap();
+        assert!(dkim.aligned);
+        assert_eq!(dkim.result, "neutral");
+        assert_eq!(dkim.org_domain.as_deref(), Some("a.com"));
+
+        let spf = trace.spf.unwrap();
+        assert_eq!(spf.mode, Alignement::Strict);
+        assert!(!spf.aligned);
+        assert_eq!(spf.domain, "bounce.a.com");
+
+        // Without sampling the failure isn't enforced, but the alignment is
+        // still traced
+        policy.pct = 0;
+        let (res, trace) = policy.apply_with_trace(&ctx);
+        assert_eq!(res.to_str(), "neutral");
+        assert_eq!(res.disposition(), ReceiverAction::None);
+        assert!(!trace.sampling.applied);
+        assert!(trace.dkim.unwrap().aligned);
+        assert!(!trace.spf.unwrap().aligned);
+    }
+
     #[test]
     fn test_check_alignement_spf_strict() {
         let mut policy = Policy::new(ReceiverAction::Reject);
@@ -330,6 +660,20 @@ mod tests {
         assert!(!policy.check_dkim_alignment(from_domain, &dkim_result));
     }
 
+    #[test]
+    fn test_check_alignement_with_org_domain_resolver() {
+        let policy = Policy::new(ReceiverAction::Reject);
+        let resolver =
+            OrgDomainResolver::from_list("com\nexample.com", Default::default()).unwrap();
+
+        assert!(policy.check_spf_alignment("a.example.com", "b.example.com"));
+        assert!(!policy.check_spf_alignment_with(&resolver, "a.example.com", "b.example.com"));
+
+        let dkim_result = cfdkim::DKIMResult::neutral("b.example.com".to_owned());
+        assert!(policy.check_dkim_alignment("a.example.com", &dkim_result));
+        assert!(!policy.check_dkim_alignment_with(&resolver, "a.example.com", &dkim_result));
+    }
+
     #[test]
     fn test_check_alignement_dkim_relaxed() {
         let mut policy = Policy::new(ReceiverAction::Reject);
@@ -0,0 +1,316 @@
+/// Postfix SMTPD access policy delegation server
+///
+/// Postfix queries the server for each recipient (or at the DATA command)
+/// with the attributes of the SMTP transaction, as `name=value` lines ended
+/// by an empty line, and expects an `action=...` line followed by an empty
+/// line. See https://www.postfix.org/SMTPD_POLICY_README.html.
+///
+/// The server discovers the DMARC policy of the envelope sender domain and
+/// caches it, so that it's already known when the message is evaluated at the
+/// end of the data (by the milter sharing the verifier). The action returned
+/// to Postfix is decided by an `AccessPolicy`.
+use crate::{DmarcVerifier, Policy};
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+/// Maximum number of attributes of a request
+const MAX_ATTRIBUTES: usize = 100;
+/// Maximum length of an attribute line, including the line ending
+const MAX_LINE_LENGTH: u64 = 8192;
+
+/// Attributes of a policy request, for instance `sender`, `client_address`
+/// or `protocol_state`
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct PolicyRequest {
+    pub attributes: HashMap<String, String>,
+}
+
+impl PolicyRequest {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.attributes.get(name).map(|v| v.as_str())
+    }
+
+    /// Domain of the envelope sender, `None` for bounces
+    pub fn sender_domain(&self) -> Option<String> {
+        self.get("sender")
+            .and_then(|sender| sender.rsplit_once('@'))
+            .map(|(_, domain)| domain.trim_end_matches('.').to_lowercase())
+            .filter(|domain| !domain.is_empty())
+    }
+}
+
+/// Decides the action returned to Postfix
+pub trait AccessPolicy: Send + Sync {
+    /// Returns the action (for instance `DUNNO`, `DEFER_IF_PERMIT ...` or
+    /// `REJECT ...`) for the request and the DMARC policy of its envelope
+    /// sender domain
+    fn action(&self, request: &PolicyRequest, policy: Option<&Policy>) -> String;
+}
+
+/// Only discovers the policies, letting Postfix continue with its other
+/// restrictions
+pub struct Dunno;
+
+impl AccessPolicy for Dunno {
+    fn action(&self, _request: &PolicyRequest, _policy: Option<&Policy>) -> String {
+        "DUNNO".to_owned()
+    }
+}
+
+/// Policy delegation server
+pub struct PolicyServer {
+    /// Verifier discovering the policies, can be shared with the milter.
+    /// Give it a `PolicyCache` as resolver to keep the policies until the
+    /// message is evaluated.
+    pub verifier: Arc<DmarcVerifier>,
+    pub access_policy: Arc<dyn AccessPolicy>,
+    /// Connections without any data for this duration are closed
+    pub idle_timeout: Duration,
+    pub logger: slog::Logger,
+}
+
+impl PolicyServer {
+    /// Creates a server answering `DUNNO`
+    pub fn new(verifier: Arc<DmarcVerifier>) -> Self {
+        Self {
+            verifier,
+            access_policy: Arc::new(Dunno),
+            // Postfix closes its idle connections after 300s (max_idle)
+            idle_timeout: Duration::from_secs(330),
+            logger: slog::Logger::root(slog::Discard, slog::o!()),
+        }
+    }
+
+    /// Discovers the policy of the envelope sender domain and returns the
+    /// action. DNS failures are logged and handled as a missing policy.
+    pub async fn handle_request(&self, request: &PolicyRequest) -> String {
+        let policy = match request.sender_domain() {
+            Some(domain) => match self.verifier.load_policy(&self.logger, &domain).await {
+                Ok(policy) => policy,
+                Err(err) => {
+                    slog::debug!(self.logger, "failed to load policy for {}: {}", domain, err);
+                    None
+                }
+            },
+            None => None,
+        };
+        self.access_policy.action(request, policy.as_ref())
+    }
+
+    /// Accepts connections from Postfix
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            let server = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(err) = server.handle_connection(stream).await {
+                    slog::warn!(
+                        server.logger,
+                        "policy connection from {} failed: {}",
+                        addr,
+                        err
+                    );
+                }
+            });
+        }
+    }
+
+    /// Accepts connections from Postfix on a Unix domain socket
+    #[cfg(unix)]
+    pub async fn serve_unix(self: Arc<Self>, listener: tokio::net::UnixListener) -> io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let server = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(err) = server.handle_connection(stream).await {
+                    slog::warn!(server.logger, "policy connection failed: {}", err);
+                }
+            });
+        }
+    }
+
+    /// Answers the requests of a connection, Postfix reuses connections for
+    /// several requests
+    pub async fn handle_connection<S>(&self, stream: S) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut stream = BufReader::new(stream);
+        let mut request = PolicyRequest::default();
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            let mut limited = (&mut stream).take(MAX_LINE_LENGTH);
+            let read = limited.read_line(&mut line);
+            let n = match tokio::time::timeout(self.idle_timeout, read).await {
+                Ok(n) => n?,
+                // Postfix is idle between the requests
+                Err(_) if request.attributes.is_empty() => return Ok(()),
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "timeout reading the policy request",
+                    ))
+                }
+            };
+            if n == 0 {
+                return Ok(());
+            }
+            if !line.ends_with('\n') && n as u64 == MAX_LINE_LENGTH {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "policy attribute line too long",
+                ));
+            }
+
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line.is_empty() {
+                let action = self.handle_request(&request).await;
+                let response = format!("action={}\n\n", action);
+                stream.get_mut().write_all(response.as_bytes()).await?;
+                stream.get_mut().flush().await?;
+                request = PolicyRequest::default();
+                continue;
+            }
+
+            let (name, value) = line.split_once('=').ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid policy attribute: {:?}", line),
+                )
+            })?;
+            if request.attributes.len() >= MAX_ATTRIBUTES {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "too many policy attributes",
+                ));
+            }
+            request.attributes.insert(name.to_owned(), value.to_owned());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::testing::TestResolver;
+    use crate::{CacheOptions, PolicyCache, ReceiverAction};
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+
+    /// Rejects the senders whose domain publishes `p=reject`
+    struct RejectStrict;
+    impl AccessPolicy for RejectStrict {
+        fn action(&self, _request: &PolicyRequest, policy: Option<&Policy>) -> String {
+            match policy {
+                Some(policy) if policy.action == ReceiverAction::Reject => {
+                    "REJECT strict DMARC policy".to_owned()
+                }
+                _ => "DUNNO".to_owned(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_policy_server() {
+        let resolver = TestResolver::default()
+            .txt("_dmarc.example.com", "v=DMARC1; p=reject")
+            .ttl(Duration::from_secs(60));
+        let queries = resolver.queries();
+        let cache = Arc::new(PolicyCache::new(resolver.build(), CacheOptions::default()));
+        let mut server = PolicyServer::new(Arc::new(DmarcVerifier::new(cache.clone())));
+        server.access_policy = Arc::new(RejectStrict);
+
+        let (mut postfix, stream) = tokio::io::duplex(4096);
+        let client = async move {
+            let requests = "request=smtpd_access_policy\nprotocol_state=RCPT\n\
+                            sender=alice@Example.com\nclient_address=192.0.2.1\n\n\
+                            request=smtpd_access_policy\nsender=bob@example.com\n\n\
+                            request=smtpd_access_policy\nsender=\n\n";
+            postfix.write_all(requests.as_bytes()).await.unwrap();
+            postfix.shutdown().await.unwrap();
+
+            let mut responses = String::new();
+            postfix.read_to_string(&mut responses).await.unwrap();
+            responses
+        };
+
+        let (res, responses) = tokio::join!(server.handle_connection(stream), client);
+        res.unwrap();
+        assert_eq!(
+            responses,
+            "action=REJECT strict DMARC policy\n\n\
+             action=REJECT strict DMARC policy\n\n\
+             action=DUNNO\n\n"
+        );
+        // The second request was answered from the cache
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.stats().hits, 1);
+    }
+
+    #[tokio::test]
+    async fn test_policy_server_overrides() {
+        let resolver = TestResolver::default().txt("_dmarc.example.com", "v=DMARC1; p=none");
+        let mut verifier = DmarcVerifier::new(resolver.build());
+        verifier.discovery_options.overrides.insert(
+            "example.com".to_owned(),
+            Policy::new(ReceiverAction::Reject),
+        );
+        let mut server = PolicyServer::new(Arc::new(verifier));
+        server.access_policy = Arc::new(RejectStrict);
+
+        let request = PolicyRequest {
+            attributes: [("sender".to_owned(), "a@sub.example.com".to_owned())].into(),
+        };
+        assert_eq!(
+            server.handle_request(&request).await,
+            "REJECT strict DMARC policy"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_policy_server_limits() {
+        let verifier = Arc::new(DmarcVerifier::new(TestResolver::default().build()));
+        let mut server = PolicyServer::new(verifier);
+        server.idle_timeout = Duration::from_millis(50);
+
+        // Line too long
+        let (mut postfix, stream) = tokio::io::duplex(4096);
+        let line = format!("sender={}\n", "a".repeat(MAX_LINE_LENGTH as usize));
+        let client = async move {
+            let _ = postfix.write_all(line.as_bytes()).await;
+            postfix
+        };
+        let (res, _postfix) = tokio::join!(server.handle_connection(stream), client);
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // Incomplete request
+        let (mut postfix, stream) = tokio::io::duplex(4096);
+        postfix.write_all(b"sender=a@example.com\n").await.unwrap();
+        let res = server.handle_connection(stream).await;
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::TimedOut);
+
+        // Idle connection between the requests
+        let (_postfix, stream) = tokio::io::duplex(4096);
+        server.handle_connection(stream).await.unwrap();
+    }
+
+    #[test]
+    fn test_sender_domain() {
+        let request = |sender: &str| PolicyRequest {
+            attributes: [("sender".to_owned(), sender.to_owned())].into(),
+        };
+        assert_eq!(
+            request("a@Example.COM.").sender_domain(),
+            Some("example.com".to_owned())
+        );
+        assert_eq!(request("").sender_domain(), None);
+        assert_eq!(PolicyRequest::default().sender_domain(), None);
+    }
+}
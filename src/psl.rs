@@ -0,0 +1,386 @@
+/// Organizational Domain computation from a Public Suffix List loaded at
+/// runtime
+use crate::{dns, DMARCError};
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+const ICANN_BEGIN: &str = "===BEGIN ICANN DOMAINS===";
+const ICANN_END: &str = "===END ICANN DOMAINS===";
+const PRIVATE_BEGIN: &str = "===BEGIN PRIVATE DOMAINS===";
+const PRIVATE_END: &str = "===END PRIVATE DOMAINS===";
+
+/// Organizational Domain resolver used by the builtin list
+pub(crate) static BUILTIN: OrgDomainResolver = OrgDomainResolver::builtin();
+
+/// Sections of the Public Suffix List to use
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct PslSections {
+    /// Domains delegated by ICANN
+    pub icann: bool,
+    /// Domains submitted by their owners
+    pub private: bool,
+}
+
+impl Default for PslSections {
+    fn default() -> Self {
+        Self {
+            icann: true,
+            private: true,
+        }
+    }
+}
+
+/// Rules parsed from a `public_suffix_list.dat` file
+#[derive(Debug, Default)]
+struct SuffixList {
+    rules: HashSet<String>,
+    wildcards: HashSet<String>,
+    exceptions: HashSet<String>,
+}
+
+impl SuffixList {
+    fn parse(contents: &str, sections: PslSections) -> Result<Self, DMARCError> {
+        let mut list = SuffixList::default();
+        // Rules outside of any section are kept
+        let mut enabled = true;
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if let Some(comment) = line.strip_prefix("//") {
+                match comment.trim() {
+                    ICANN_BEGIN => enabled = sections.icann,
+                    PRIVATE_BEGIN => enabled = sections.private,
+                    ICANN_END | PRIVATE_END => enabled = true,
+                    _ => {}
+                }
+                continue;
+            }
+
+            // Only the first word of the line is the rule
+            let rule = match line.split_whitespace().next() {
+                Some(rule) if enabled => rule.to_lowercase(),
+                _ => continue,
+            };
+            if let Some(rule) = rule.strip_prefix('!') {
+                list.exceptions.insert(to_ascii(rule));
+            } else if let Some(rule) = rule.strip_prefix("*.") {
+                list.wildcards.insert(to_ascii(rule));
+            } else {
+                list.rules.insert(to_ascii(&rule));
+            }
+        }
+
+        if list.rules.is_empty() && list.wildcards.is_empty() {
+            return Err(DMARCError::InvalidPublicSuffixList(
+                "no rules found".to_owned(),
+            ));
+        }
+        Ok(list)
+    }
+
+    /// Returns the number of labels of the public suffix of the domain
+    fn public_suffix_len(&self, labels: &[&str]) -> usize {
+        for i in 0..labels.len() {
+            let suffix = labels[i..].join(".");
+            if self.exceptions.contains(&suffix) {
+                return labels.len() - i - 1;
+            }
+            if self.rules.contains(&suffix) {
+                return labels.len() - i;
+            }
+            if i + 1 < labels.len() && self.wildcards.contains(&labels[i + 1..].join(".")) {
+                return labels.len() - i;
+            }
+        }
+        // Implicit `*` rule
+        1
+    }
+
+    // https://datatracker.ietf.org/doc/html/rfc7489#section-3.2
+    fn organizational_domain(&self, domain: &str) -> Option<String> {
+        let domain = to_ascii(&domain.trim_end_matches('.').to_lowercase());
+        let labels: Vec<&str> = domain.split('.').collect();
+        if labels.iter().any(|label| label.is_empty()) {
+            return None;
+        }
+
+        let len = self.public_suffix_len(&labels) + 1;
+        if len > labels.len() {
+            return None;
+        }
+        Some(labels[labels.len() - len..].join("."))
+    }
+}
+
+/// Converts the U-labels to A-labels, as the names are queried in DNS. The
+/// list uses U-labels, for instance `公司.cn` instead of `xn--55qx5d.cn`.
+fn to_ascii(domain: &str) -> String {
+    if domain.is_ascii() {
+        return domain.to_owned();
+    }
+    idna::domain_to_ascii(domain).unwrap_or_else(|_| domain.to_owned())
+}
+
+#[derive(Default)]
+struct State {
+    list: Option<Arc<SuffixList>>,
+    modified: Option<SystemTime>,
+}
+
+/// Computes Organizational Domains
+///
+/// By default the Public Suffix List compiled in the `addr` crate is used. A
+/// `public_suffix_list.dat` file can be loaded instead, and reloaded while
+/// the resolver is in use.
+pub struct OrgDomainResolver {
+    state: RwLock<State>,
+    path: Option<PathBuf>,
+    sections: PslSections,
+}
+
+impl OrgDomainResolver {
+    /// Uses the Public Suffix List compiled in the `addr` crate
+    pub const fn builtin() -> Self {
+        Self {
+            state: RwLock::new(State {
+                list: None,
+                modified: None,
+            }),
+            path: None,
+            sections: PslSections {
+                icann: true,
+                private: true,
+            },
+        }
+    }
+
+    /// Uses the rules of a Public Suffix List in the `public_suffix_list.dat`
+    /// format
+    pub fn from_list(contents: &str, sections: PslSections) -> Result<Self, DMARCError> {
+        let list = SuffixList::parse(contents, sections)?;
+        Ok(Self {
+            state: RwLock::new(State {
+                list: Some(Arc::new(list)),
+                modified: None,
+            }),
+            path: None,
+            sections,
+        })
+    }
+
+    /// Loads a Public Suffix List file, which can later be reloaded with
+    /// `reload`
+    pub fn from_file<P: AsRef<Path>>(path: P, sections: PslSections) -> Result<Self, DMARCError> {
+        let resolver = Self {
+            state: RwLock::new(State::default()),
+            path: Some(path.as_ref().to_owned()),
+            sections,
+        };
+        resolver.reload()?;
+        Ok(resolver)
+    }
+
+    /// Reloads the Public Suffix List file. On error the previous list is
+    /// kept.
+    pub fn reload(&self) -> Result<(), DMARCError> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let contents = std::fs::read_to_string(path).map_err(|err| {
+            DMARCError::InvalidPublicSuffixList(format!("failed to read {:?}: {}", path, err))
+        })?;
+        self.replace(&contents)?;
+        self.state.write().unwrap().modified = modified;
+        Ok(())
+    }
+
+    /// Reloads the Public Suffix List file if it was modified since it was
+    /// last loaded. Returns whether it was reloaded.
+    pub fn reload_if_modified(&self) -> Result<bool, DMARCError> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(false),
+        };
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        if modified.is_some() && modified == self.state.read().unwrap().modified {
+            return Ok(false);
+        }
+        self.reload()?;
+        Ok(true)
+    }
+
+    /// Replaces the rules with the ones of the given Public Suffix List
+    pub fn replace(&self, contents: &str) -> Result<(), DMARCError> {
+        let list = SuffixList::parse(contents, self.sections)?;
+        self.state.write().unwrap().list = Some(Arc::new(list));
+        Ok(())
+    }
+
+    /// Returns the Organizational Domain of the domain, `None` if the domain
+    /// is a public suffix or invalid
+    pub fn organizational_domain(&self, domain: &str) -> Option<String> {
+        let list = self.state.read().unwrap().list.clone();
+        match list {
+            Some(list) => list.organizational_domain(domain),
+            None => dns::get_root_domain_name(domain),
+        }
+    }
+}
+
+impl Default for OrgDomainResolver {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+impl fmt::Debug for OrgDomainResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OrgDomainResolver")
+            .field("path", &self.path)
+            .field("sections", &self.sections)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIST: &str = "
+// ===BEGIN ICANN DOMAINS===
+com
+uk
+co.uk
+*.ck
+!www.ck
+cn
+公司.cn
+// ===END ICANN DOMAINS===
+// ===BEGIN PRIVATE DOMAINS===
+github.io
+// ===END PRIVATE DOMAINS===
+";
+
+    #[test]
+    fn test_organizational_domain() {
+        let resolver = OrgDomainResolver::from_list(LIST, PslSections::default()).unwrap();
+
+        assert_eq!(
+            resolver.organizational_domain("a.b.example.com").as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(
+            resolver
+                .organizational_domain("Mail.Example.CO.UK.")
+                .as_deref(),
+            Some("example.co.uk")
+        );
+        assert_eq!(
+            resolver.organizational_domain("a.b.c.ck").as_deref(),
+            Some("b.c.ck")
+        );
+        assert_eq!(
+            resolver.organizational_domain("a.www.ck").as_deref(),
+            Some("www.ck")
+        );
+        assert_eq!(
+            resolver
+                .organizational_domain("a.user.github.io")
+                .as_deref(),
+            Some("user.github.io")
+        );
+        // Implicit `*` rule
+        assert_eq!(
+            resolver.organizational_domain("a.example.test").as_deref(),
+            Some("example.test")
+        );
+        assert_eq!(resolver.organizational_domain("co.uk"), None);
+
+        // The IDN rules are converted to A-labels
+        assert_eq!(
+            resolver
+                .organizational_domain("a.example.xn--55qx5d.cn")
+                .as_deref(),
+            Some("example.xn--55qx5d.cn")
+        );
+        assert_eq!(
+            resolver
+                .organizational_domain("a.example.公司.cn")
+                .as_deref(),
+            Some("example.xn--55qx5d.cn")
+        );
+        assert_eq!(resolver.organizational_domain("xn--55qx5d.cn"), None);
+    }
+
+    #[test]
+    fn test_sections() {
+        let sections = PslSections {
+            icann: true,
+            private: false,
+        };
+        let resolver = OrgDomainResolver::from_list(LIST, sections).unwrap();
+        assert_eq!(
+            resolver
+                .organizational_domain("a.user.github.io")
+                .as_deref(),
+            Some("github.io")
+        );
+    }
+
+    #[test]
+    fn test_replace() {
+        let resolver = OrgDomainResolver::from_list(LIST, PslSections::default()).unwrap();
+        assert_eq!(
+            resolver.organizational_domain("a.example.co.uk").as_deref(),
+            Some("example.co.uk")
+        );
+
+        resolver.replace("uk").unwrap();
+        assert_eq!(
+            resolver.organizational_domain("a.example.co.uk").as_deref(),
+            Some("co.uk")
+        );
+
+        assert!(resolver.replace("// empty").is_err());
+        assert_eq!(
+            resolver.organizational_domain("a.example.co.uk").as_deref(),
+            Some("co.uk")
+        );
+    }
+
+    #[test]
+    fn test_reload_file() {
+        let path = std::env::temp_dir().join(format!("dmarc-psl-{}.dat", std::process::id()));
+        std::fs::write(&path, LIST).unwrap();
+
+        let resolver = OrgDomainResolver::from_file(&path, PslSections::default()).unwrap();
+        assert_eq!(
+            resolver.organizational_domain("a.example.co.uk").as_deref(),
+            Some("example.co.uk")
+        );
+        assert!(!resolver.reload_if_modified().unwrap());
+
+        std::fs::write(&path, "uk").unwrap();
+        resolver.reload().unwrap();
+        assert_eq!(
+            resolver.organizational_domain("a.example.co.uk").as_deref(),
+            Some("co.uk")
+        );
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(resolver.reload().is_err());
+    }
+
+    #[test]
+    fn test_builtin() {
+        assert_eq!(
+            BUILTIN.organizational_domain("a.example.co.uk").as_deref(),
+            Some("example.co.uk")
+        );
+    }
+}
@@ -0,0 +1,356 @@
+/// Generation of DMARC policy records
+use crate::parser;
+use crate::policy::{Alignement, Policy, PublicSuffixDomain, ReceiverAction, ReportUri};
+use crate::DMARCError;
+
+/// Maximum length of a TXT character string
+// https://datatracker.ietf.org/doc/html/rfc1035#section-3.3
+const TXT_STRING_MAX_LEN: usize = 255;
+
+/// Options of the record rendering
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct RecordOptions {
+    /// Omit the tags set to their default value (`adkim`, `aspf`, `pct`, `fo`
+    /// and `ri`)
+    pub omit_defaults: bool,
+}
+
+/// Builds a valid DMARC policy, to be rendered with `Policy::to_record`
+#[derive(Debug, Clone)]
+pub struct PolicyBuilder {
+    policy: Policy,
+}
+
+impl PolicyBuilder {
+    pub fn new(action: ReceiverAction) -> Self {
+        Self {
+            policy: Policy::new(action),
+        }
+    }
+
+    /// Sets the policy for subdomains (`sp` tag)
+    pub fn subdomain_action(mut self, action: ReceiverAction) -> Self {
+        self.policy.sp = Some(action);
+        self
+    }
+
+    /// Sets the policy for non-existent subdomains (`np` tag)
+    pub fn non_existent_action(mut self, action: ReceiverAction) -> Self {
+        self.policy.np = Some(action);
+        self
+    }
+
+    pub fn adkim(mut self, mode: Alignement) -> Self {
+        self.policy.adkim = mode;
+        self
+    }
+
+    pub fn aspf(mut self, mode: Alignement) -> Self {
+        self.policy.aspf = mode;
+        self
+    }
+
+    pub fn pct(mut self, pct: usize) -> Self {
+        self.policy.pct = pct;
+        self
+    }
+
+    pub fn psd(mut self, psd: PublicSuffixDomain) -> Self {
+        self.policy.psd = psd;
+        self
+    }
+
+    pub fn testing(mut self, testing: bool) -> Self {
+        self.policy.testing = testing;
+        self
+    }
+
+    /// Adds an aggregate report URI, with an optional maximum size in bytes
+    pub fn rua(mut self, uri: &str, max_size: Option<u64>) -> Self {
+        self.policy.rua.push(ReportUri {
+            uri: uri.to_owned(),
+            max_size,
+        });
+        self
+    }
+
+    /// Adds a failure report URI, with an optional maximum size in bytes
+    pub fn ruf(mut self, uri: &str, max_size: Option<u64>) -> Self {
+        self.policy.ruf.push(ReportUri {
+            uri: uri.to_owned(),
+            max_size,
+        });
+        self
+    }
+
+    /// Sets the failure reporting options (`fo` tag), for instance `1:d`
+    pub fn failure_options(mut self, fo: &str) -> Self {
+        self.policy.fo = fo.to_owned();
+        self
+    }
+
+    /// Sets the interval in seconds between aggregate reports (`ri` tag)
+    pub fn report_interval(mut self, ri: u32) -> Self {
+        self.policy.ri = ri;
+        self
+    }
+
+    /// Validates the policy so that its record can be parsed back
+    pub fn build(self) -> Result<Policy, DMARCError> {
+        let policy = self.policy;
+
+        if policy.pct > 100 {
+            return Err(DMARCError::InvalidPolicy(format!(
+                "pct must be at most 100: {}",
+                policy.pct
+            )));
+        }
+        if parser::parse_failure_options(&policy.fo).as_ref() != Some(&policy.fo) {
+            return Err(DMARCError::InvalidPolicy(format!(
+                "invalid failure options: {}",
+                policy.fo
+            )));
+        }
+        for uri in policy.rua.iter().chain(&policy.ruf) {
+            // Commas, exclamation points and semicolons must be percent-encoded
+            // https://datatracker.ietf.org/doc/html/rfc7489#section-6.2
+            let valid =
+                !uri.uri.contains([',', '!', ';']) && parser::parse_report_uri(&uri.uri).is_some();
+            if !valid {
+                return Err(DMARCError::InvalidPolicy(format!(
+                    "invalid report URI: {}",
+                    uri.uri
+                )));
+            }
+        }
+
+        Ok(policy)
+    }
+}
+
+impl Policy {
+    /// Renders the policy as a DMARC record, with all the tags
+    ///
+    /// The `p` tag is set to the published one, not to the action which is
+    /// the `sp` tag for policies found at the Organizational Domain. Parsing
+    /// the record back results in the same policy, apart from the raw
+    /// `record` and `tags`. Unknown tags aren't rendered.
+    ///
+    /// Repaired policies are rendered as their original record, since their
+    /// `p` or `sp` tag is invalid.
+    pub fn to_record(&self) -> String {
+        self.to_record_with(&RecordOptions::default())
+    }
+
+    /// Renders the policy as a DMARC record
+    pub fn to_record_with(&self, options: &RecordOptions) -> String {
+        if self.repaired && !self.record.is_empty() {
+            return self.record.clone();
+        }
+        let defaults = Policy::new(self.p.clone());
+        let include = |is_default: bool| !(options.omit_defaults && is_default);
+
+        let mut tags = vec!["v=DMARC1".to_owned(), format!("p={}", self.p.to_str())];
+        if let Some(sp) = &self.sp {
+            tags.push(format!("sp={}", sp.to_str()));
+        }
+        if let Some(np) = &self.np {
+            tags.push(format!("np={}", np.to_str()));
+        }
+        match self.psd {
+            PublicSuffixDomain::Yes => tags.push("psd=y".to_owned()),
+            PublicSuffixDomain::No => tags.push("psd=n".to_owned()),
+            PublicSuffixDomain::Unknown => {}
+        }
+        if self.testing {
+            tags.push("t=y".to_owned());
+        }
+        if include(self.adkim == defaults.adkim) {
+            tags.push(format!("adkim={}", alignement_to_str(&self.adkim)));
+        }
+        if include(self.aspf == defaults.aspf) {
+            tags.push(format!("aspf={}", alignement_to_str(&self.aspf)));
+        }
+        if include(self.pct == defaults.pct) {
+            tags.push(format!("pct={}", self.pct));
+        }
+        if include(self.fo == defaults.fo) {
+            tags.push(format!("fo={}", self.fo));
+        }
+        if include(self.ri == defaults.ri) {
+            tags.push(format!("ri={}", self.ri));
+        }
+        if !self.rua.is_empty() {
+            tags.push(format!("rua={}", format_report_uris(&self.rua)));
+        }
+        if !self.ruf.is_empty() {
+            tags.push(format!("ruf={}", format_report_uris(&self.ruf)));
+        }
+
+        tags.join("; ")
+    }
+
+    /// Renders the policy as the character strings of a TXT record, each at
+    /// most 255 bytes long
+    pub fn to_txt_strings(&self, options: &RecordOptions) -> Vec<String> {
+        split_txt_strings(&self.to_record_with(options))
+    }
+}
+
+fn alignement_to_str(mode: &Alignement) -> &'static str {
+    match mode {
+        Alignement::Relaxed => "r",
+        Alignement::Strict => "s",
+    }
+}
+
+fn format_report_uris(uris: &[ReportUri]) -> String {
+    uris.iter()
+        .map(|uri| match uri.max_size {
+            Some(size) => format!("{}!{}", uri.uri, format_size(size)),
+            None => uri.uri.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Formats the size with the largest unit dividing it
+fn format_size(size: u64) -> String {
+    for (unit, shift) in [('t', 40), ('g', 30), ('m', 20), ('k', 10)] {
+        if size != 0 && size.trailing_zeros() >= shift {
+            return format!("{}{}", size >> shift, unit);
+        }
+    }
+    size.to_string()
+}
+
+/// Splits the record into TXT character strings, without splitting UTF-8
+/// characters. Receivers concatenate them without separator.
+fn split_txt_strings(record: &str) -> Vec<String> {
+    let mut strings = vec![];
+    let mut current = String::new();
+    for c in record.chars() {
+        if current.len() + c.len_utf8() > TXT_STRING_MAX_LEN {
+            strings.push(std::mem::take(&mut current));
+        }
+        current.push(c);
+    }
+    if !current.is_empty() || strings.is_empty() {
+        strings.push(current);
+    }
+    strings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_policy;
+
+    #[test]
+    fn test_to_record() {
+        let policy = PolicyBuilder::new(ReceiverAction::Reject)
+            .subdomain_action(ReceiverAction::Quarantine)
+            .adkim(Alignement::Strict)
+            .pct(50)
+            .rua("mailto:dmarc@example.com", Some(10 * 1024 * 1024))
+            .rua("mailto:dmarc@example.net", None)
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            policy.to_record(),
+            "v=DMARC1; p=reject; sp=quarantine; adkim=s; aspf=r; pct=50; fo=0; ri=86400; \
+             rua=mailto:dmarc@example.com!10m,mailto:dmarc@example.net"
+        );
+        let options = RecordOptions {
+            omit_defaults: true,
+        };
+        assert_eq!(
+            policy.to_record_with(&options),
+            "v=DMARC1; p=reject; sp=quarantine; adkim=s; pct=50; \
+             rua=mailto:dmarc@example.com!10m,mailto:dmarc@example.net"
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let policies = vec![
+            PolicyBuilder::new(ReceiverAction::None).build().unwrap(),
+            PolicyBuilder::new(ReceiverAction::Quarantine)
+                .non_existent_action(ReceiverAction::Reject)
+                .psd(PublicSuffixDomain::Yes)
+                .testing(true)
+                .aspf(Alignement::Strict)
+                .failure_options("1:d")
+                .report_interval(3600)
+                .ruf("mailto:ruf@example.com", Some(1500))
+                .rua("https://example.com/reports", Some(2048))
+                .build()
+                .unwrap(),
+        ];
+
+        for policy in policies {
+            for omit_defaults in [false, true] {
+                let record = policy.to_record_with(&RecordOptions { omit_defaults });
+                let parsed = parse_policy(&record, false).unwrap();
+                assert!(parsed.is_equivalent(&policy), "{}", record);
+                assert!(parsed != policy);
+            }
+        }
+    }
+
+    #[test]
+    fn test_round_trip_root() {
+        // At the Organizational Domain the action is the `sp` tag, but `p`
+        // is still rendered
+        let policy = parse_policy("v=DMARC1; p=reject; sp=quarantine; adkim=s", true).unwrap();
+        assert_eq!(policy.action, ReceiverAction::Quarantine);
+
+        let record = policy.to_record_with(&RecordOptions {
+            omit_defaults: true,
+        });
+        assert_eq!(record, "v=DMARC1; p=reject; sp=quarantine; adkim=s");
+        assert_eq!(parse_policy(&record, true).unwrap(), policy);
+
+        // Repaired policies keep their record
+        let record = "v=DMARC1; p=reject; sp=hein; rua=mailto:a@example.com";
+        let policy = parse_policy(record, true).unwrap();
+        assert_eq!(policy.to_record(), record);
+    }
+
+    #[test]
+    fn test_builder_validation() {
+        assert!(PolicyBuilder::new(ReceiverAction::None)
+            .pct(101)
+            .build()
+            .is_err());
+        assert!(PolicyBuilder::new(ReceiverAction::None)
+            .failure_options("2")
+            .build()
+            .is_err());
+        assert!(PolicyBuilder::new(ReceiverAction::None)
+            .rua("mailto:a@example.com,mailto:b@example.com", None)
+            .build()
+            .is_err());
+        assert!(PolicyBuilder::new(ReceiverAction::None)
+            .ruf("example.com", None)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_to_txt_strings() {
+        let mut builder = PolicyBuilder::new(ReceiverAction::Reject);
+        for i in 0..20 {
+            builder = builder.rua(&format!("mailto:dmarc-reports-{}@example.com", i), None);
+        }
+        let policy = builder.build().unwrap();
+
+        let strings = policy.to_txt_strings(&RecordOptions::default());
+        assert!(strings.len() > 1);
+        assert!(strings.iter().all(|s| s.len() <= 255));
+        assert_eq!(strings.concat(), policy.to_record());
+
+        assert_eq!(split_txt_strings(""), vec![""]);
+    }
+}
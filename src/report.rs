@@ -0,0 +1,251 @@
+/// Parsing of the aggregate reports sent to the `rua` addresses
+use crate::DMARCError;
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+/// Maximum size of a decompressed report, reports come from third parties
+const MAX_REPORT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Aggregate feedback report, as specified in
+/// https://datatracker.ietf.org/doc/html/rfc7489#appendix-C
+///
+/// Only the elements needed to summarize reports are parsed, the others are
+/// ignored.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct AggregateReport {
+    pub report_metadata: ReportMetadata,
+    pub policy_published: PolicyPublished,
+    #[serde(rename = "record", default)]
+    pub records: Vec<ReportRecord>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ReportMetadata {
+    /// Name of the reporting organization
+    pub org_name: String,
+    pub email: Option<String>,
+    pub report_id: String,
+    pub date_range: DateRange,
+}
+
+/// Time range of the report, in seconds since epoch
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct DateRange {
+    pub begin: u64,
+    pub end: u64,
+}
+
+/// Policy found by the reporter
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct PolicyPublished {
+    pub domain: String,
+    pub adkim: Option<String>,
+    pub aspf: Option<String>,
+    pub p: String,
+    pub sp: Option<String>,
+    pub pct: Option<u32>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ReportRecord {
+    pub row: ReportRow,
+    pub identifiers: Identifiers,
+    #[serde(default)]
+    pub auth_results: AuthResults,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ReportRow {
+    pub source_ip: String,
+    /// Number of messages
+    pub count: u64,
+    pub policy_evaluated: PolicyEvaluated,
+}
+
+/// Result of the policy evaluation by the reporter
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct PolicyEvaluated {
+    /// Applied disposition: none, quarantine or reject
+    pub disposition: String,
+    /// DKIM aligned result: pass or fail
+    pub dkim: String,
+    /// SPF aligned result: pass or fail
+    pub spf: String,
+}
+
+impl PolicyEvaluated {
+    /// DMARC passes when DKIM or SPF passed and was aligned
+    pub fn passed(&self) -> bool {
+        self.dkim == "pass" || self.spf == "pass"
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Identifiers {
+    pub header_from: String,
+    pub envelope_from: Option<String>,
+}
+
+/// Raw, unaligned, authentication results
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct AuthResults {
+    #[serde(default)]
+    pub dkim: Vec<AuthResult>,
+    #[serde(default)]
+    pub spf: Vec<AuthResult>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct AuthResult {
+    pub domain: String,
+    pub result: String,
+}
+
+impl AggregateReport {
+    /// Parses the XML report
+    pub fn from_xml(xml: &str) -> Result<Self, DMARCError> {
+        quick_xml::de::from_str(xml).map_err(|err| DMARCError::InvalidReport(err.to_string()))
+    }
+
+    /// Parses the report, gzip compressed or not
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DMARCError> {
+        if !bytes.starts_with(GZIP_MAGIC) {
+            let xml = std::str::from_utf8(bytes)
+                .map_err(|err| DMARCError::InvalidReport(err.to_string()))?;
+            return Self::from_xml(xml);
+        }
+        Self::from_xml(&gunzip(bytes, MAX_REPORT_SIZE)?)
+    }
+}
+
+/// Decompresses at most `limit` bytes
+fn gunzip(bytes: &[u8], limit: u64) -> Result<String, DMARCError> {
+    let mut xml = String::new();
+    flate2::read::GzDecoder::new(bytes)
+        .take(limit + 1)
+        .read_to_string(&mut xml)
+        .map_err(|err| DMARCError::InvalidReport(format!("invalid gzip: {}", err)))?;
+    if xml.len() as u64 > limit {
+        return Err(DMARCError::InvalidReport(format!(
+            "decompressed report larger than {} bytes",
+            limit
+        )));
+    }
+    Ok(xml)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const REPORT: &str = r#"<?xml version="1.0" encoding="UTF-8" ?>
+<feedback>
+  <report_metadata>
+    <org_name>google.com</org_name>
+    <email>noreply-dmarc-support@google.com</email>
+    <report_id>5717107811868587391</report_id>
+    <date_range>
+      <begin>1335571200</begin>
+      <end>1335657599</end>
+    </date_range>
+  </report_metadata>
+  <policy_published>
+    <domain>example.com</domain>
+    <adkim>r</adkim>
+    <aspf>r</aspf>
+    <p>none</p>
+    <sp>none</sp>
+    <pct>100</pct>
+  </policy_published>
+  <record>
+    <row>
+      <source_ip>192.0.2.1</source_ip>
+      <count>2</count>
+      <policy_evaluated>
+        <disposition>none</disposition>
+        <dkim>fail</dkim>
+        <spf>pass</spf>
+      </policy_evaluated>
+    </row>
+    <identifiers>
+      <header_from>example.com</header_from>
+    </identifiers>
+    <auth_results>
+      <dkim>
+        <domain>example.com</domain>
+        <result>fail</result>
+        <human_result></human_result>
+      </dkim>
+      <spf>
+        <domain>example.com</domain>
+        <result>pass</result>
+      </spf>
+    </auth_results>
+  </record>
+  <record>
+    <row>
+      <source_ip>198.51.100.7</source_ip>
+      <count>1</count>
+      <policy_evaluated>
+        <disposition>reject</disposition>
+        <dkim>fail</dkim>
+        <spf>fail</spf>
+      </policy_evaluated>
+    </row>
+    <identifiers>
+      <header_from>example.com</header_from>
+    </identifiers>
+    <auth_results>
+      <spf>
+        <domain>spammer.example</domain>
+        <result>pass</result>
+      </spf>
+    </auth_results>
+  </record>
+</feedback>"#;
+
+    #[test]
+    fn test_from_xml() {
+        let report = AggregateReport::from_xml(REPORT).unwrap();
+        assert_eq!(report.report_metadata.org_name, "google.com");
+        assert_eq!(report.report_metadata.date_range.begin, 1335571200);
+        assert_eq!(report.policy_published.domain, "example.com");
+        assert_eq!(report.policy_published.pct, Some(100));
+        assert_eq!(report.records.len(), 2);
+
+        let record = &report.records[0];
+        assert_eq!(record.row.source_ip, "192.0.2.1");
+        assert_eq!(record.row.count, 2);
+        assert!(record.row.policy_evaluated.passed());
+        assert_eq!(record.auth_results.dkim[0].result, "fail");
+
+        let record = &report.records[1];
+        assert!(!record.row.policy_evaluated.passed());
+        assert!(record.auth_results.dkim.is_empty());
+        assert_eq!(record.auth_results.spf[0].domain, "spammer.example");
+    }
+
+    #[test]
+    fn test_from_bytes_gzip() {
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(REPORT.as_bytes()).unwrap();
+        let gzip = encoder.finish().unwrap();
+
+        let report = AggregateReport::from_bytes(&gzip).unwrap();
+        assert_eq!(
+            report,
+            AggregateReport::from_bytes(REPORT.as_bytes()).unwrap()
+        );
+
+        assert!(AggregateReport::from_bytes(&gzip[..20]).is_err());
+        assert!(AggregateReport::from_xml("<feedback></feedback>").is_err());
+
+        assert_eq!(gunzip(&gzip, REPORT.len() as u64).unwrap(), REPORT);
+        assert!(matches!(
+            gunzip(&gzip, REPORT.len() as u64 - 1),
+            Err(DMARCError::InvalidReport(_))
+        ));
+    }
+}
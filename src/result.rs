@@ -1,6 +1,8 @@
 use crate::policy;
 
 #[derive(PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 enum Value {
     None,
     Neutral,
@@ -9,7 +11,9 @@ enum Value {
 }
 
 /// Result of applying a DMARC policy
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DMARCResult {
+    #[cfg_attr(feature = "serde", serde(rename = "result"))]
     value: Value,
     policy: Option<policy::Policy>,
 }
@@ -25,7 +29,8 @@ impl DMARCResult {
         }
     }
 
-    /// Constructs a neutral result
+    /// Constructs a neutral result: DMARC failed but the policy wasn't
+    /// applied because of its `pct` tag
     pub fn neutral(policy: policy::Policy) -> Self {
         Self {
             value: Value::Neutral,
@@ -57,6 +62,72 @@ impl DMARCResult {
         }
     }
 
+    /// Returns the policy that produced the result, if any
+    pub fn policy(&self) -> Option<&policy::Policy> {
+        self.policy.as_ref()
+    }
+
+    /// Returns the action requested by the domain owner for this message:
+    /// the policy's action if DMARC failed, `none` otherwise
+    pub fn disposition(&self) -> policy::ReceiverAction {
+        match &self.policy {
+            Some(policy) if self.value == Value::Fail => policy.action.clone(),
+            _ => policy::ReceiverAction::None,
+        }
+    }
+
+    /// Checks if the result is more restrictive than another one. Failures
+    /// are ranked by their disposition, then come neutral, pass and none.
+    pub fn is_more_restrictive_than(&self, other: &DMARCResult) -> bool {
+        self.rank() > other.rank()
+    }
+
+    fn rank(&self) -> (u8, u8) {
+        let value = match self.value {
+            Value::None => 0,
+            Value::Pass => 1,
+            Value::Neutral => 2,
+            Value::Fail => 3,
+        };
+        let action = match self.disposition() {
+            policy::ReceiverAction::None => 0,
+            policy::ReceiverAction::Quarantine => 1,
+            policy::ReceiverAction::Reject => 2,
+        };
+        (value, action)
+    }
+
+    /// Returns the `dmarc` method of the `Authentication-Results` header, as
+    /// registered in https://datatracker.ietf.org/doc/html/rfc7489#section-11.2
+    ///
+    /// For instance `dmarc=fail (p=reject dis=reject) header.from=example.com`.
+    pub fn authentication_results(&self, header_from: &str) -> String {
+        let header_from = header_from.to_lowercase();
+        let policy = match &self.policy {
+            Some(policy) => policy,
+            None => return format!("dmarc=none header.from={}", header_from),
+        };
+        let p = policy.p.to_str();
+
+        match self.value {
+            Value::None => format!("dmarc=none header.from={}", header_from),
+            Value::Pass => format!("dmarc=pass (p={} dis=none) header.from={}", p, header_from),
+            // `neutral` isn't a registered result: DMARC failed but the policy
+            // wasn't applied because of the `pct` tag
+            // https://datatracker.ietf.org/doc/html/rfc8601#section-2.3
+            Value::Neutral => format!(
+                "dmarc=fail (p={} dis=none pct={} policy.applied=no) header.from={}",
+                p, policy.pct, header_from
+            ),
+            Value::Fail => format!(
+                "dmarc=fail (p={} dis={}) header.from={}",
+                p,
+                self.disposition().to_str(),
+                header_from
+            ),
+        }
+    }
+
     /// Checks if the email is supposed to be reject based on the DMARC policy and
     /// its result
     pub fn should_reject(&self) -> bool {
@@ -67,3 +138,49 @@ impl DMARCResult {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::{Policy, ReceiverAction};
+
+    #[test]
+    fn test_authentication_results() {
+        let mut policy = Policy::new(ReceiverAction::Quarantine);
+        assert_eq!(
+            DMARCResult::fail(policy.clone()).authentication_results("Example.com"),
+            "dmarc=fail (p=quarantine dis=quarantine) header.from=example.com"
+        );
+        assert_eq!(
+            DMARCResult::pass(policy.clone()).authentication_results("example.com"),
+            "dmarc=pass (p=quarantine dis=none) header.from=example.com"
+        );
+        // The failure is still reported when the policy isn't applied
+        policy.pct = 10;
+        assert_eq!(
+            DMARCResult::neutral(policy).authentication_results("example.com"),
+            "dmarc=fail (p=quarantine dis=none pct=10 policy.applied=no) header.from=example.com"
+        );
+        assert_eq!(
+            DMARCResult::none().authentication_results("example.com"),
+            "dmarc=none header.from=example.com"
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let result = DMARCResult::fail(Policy::new(ReceiverAction::Reject));
+        let json = serde_json::to_value(&result).unwrap();
+        assert_eq!(json["result"], "fail");
+        assert_eq!(json["policy"]["action"], "reject");
+
+        let back: DMARCResult = serde_json::from_value(json).unwrap();
+        assert_eq!(back.to_str(), "fail");
+        assert!(back.should_reject());
+
+        let json = serde_json::to_value(DMARCResult::none()).unwrap();
+        assert_eq!(json["result"], "none");
+        assert_eq!(json["policy"], serde_json::Value::Null);
+    }
+}
@@ -0,0 +1,217 @@
+/// Expansion of the SPF macros
+// https://datatracker.ietf.org/doc/html/rfc7208#section-7
+use std::net::IpAddr;
+
+const DELIMITERS: &[char] = &['.', '-', '+', ',', '/', '_', '='];
+
+/// Values of the macros
+pub(crate) struct Context<'a> {
+    /// `<sender>` of `check_host()`, `postmaster@domain` if the local part
+    /// was empty
+    pub(crate) sender: &'a str,
+    /// Current domain
+    pub(crate) domain: &'a str,
+    pub(crate) ip: IpAddr,
+    pub(crate) helo: &'a str,
+}
+
+/// Checks the syntax of the macros. `c`, `r` and `t` are only allowed in
+/// explanations.
+pub(crate) fn validate(spec: &str, explanation: bool) -> Result<(), String> {
+    process(spec, explanation, None).map(|_| ())
+}
+
+/// Expands the macros of the domain spec
+pub(crate) fn expand(spec: &str, ctx: &Context) -> Result<String, String> {
+    process(spec, false, Some(ctx))
+}
+
+fn process(spec: &str, explanation: bool, ctx: Option<&Context>) -> Result<String, String> {
+    let invalid = || format!("invalid macro in {:?}", spec);
+    let mut res = String::new();
+    let mut chars = spec.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            res.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => res.push('%'),
+            Some('_') => res.push(' '),
+            Some('-') => res.push_str("%20"),
+            Some('{') => {
+                let rest = chars.as_str();
+                let end = rest.find('}').ok_or_else(invalid)?;
+                let body = &rest[..end];
+                chars = rest[end + 1..].chars();
+                let expanded = expand_macro(body, explanation, ctx).ok_or_else(invalid)?;
+                res.push_str(&expanded);
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(res)
+}
+
+/// Expands `letter [digits] [r] [delimiters]`, `None` if invalid
+fn expand_macro(body: &str, explanation: bool, ctx: Option<&Context>) -> Option<String> {
+    let letter = body.chars().next()?;
+    let rest = &body[letter.len_utf8()..];
+    let digits_end = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    let (digits, rest) = rest.split_at(digits_end);
+    let (reverse, delimiters) = match rest.strip_prefix(['r', 'R']) {
+        Some(delimiters) => (true, delimiters),
+        None => (false, rest),
+    };
+    if !delimiters.chars().all(|c| DELIMITERS.contains(&c)) {
+        return None;
+    }
+    let keep = match digits {
+        "" => None,
+        digits => match digits.parse::<usize>() {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => Some(n),
+        },
+    };
+
+    let valid_letter = match letter.to_ascii_lowercase() {
+        's' | 'l' | 'o' | 'd' | 'i' | 'p' | 'v' | 'h' => true,
+        'c' | 'r' | 't' => explanation,
+        _ => false,
+    };
+    if !valid_letter {
+        return None;
+    }
+    let ctx = match ctx {
+        Some(ctx) => ctx,
+        None => return Some(String::new()),
+    };
+
+    let (local_part, sender_domain) = ctx.sender.rsplit_once('@').unwrap_or(("", ctx.sender));
+    let value = match letter.to_ascii_lowercase() {
+        's' => ctx.sender.to_owned(),
+        'l' => local_part.to_owned(),
+        'o' => sender_domain.to_owned(),
+        'd' => ctx.domain.to_owned(),
+        'i' => dotted_ip(ctx.ip),
+        // Validating the domain name requires PTR lookups, RFC7208
+        // recommends not using this macro and allows `unknown`
+        'p' => "unknown".to_owned(),
+        'v' => match ctx.ip {
+            IpAddr::V4(_) => "in-addr".to_owned(),
+            IpAddr::V6(_) => "ip6".to_owned(),
+        },
+        'h' => ctx.helo.to_owned(),
+        _ => return None,
+    };
+
+    let delimiters: Vec<char> = if delimiters.is_empty() {
+        vec!['.']
+    } else {
+        delimiters.chars().collect()
+    };
+    let mut parts: Vec<&str> = value.split(delimiters.as_slice()).collect();
+    if reverse {
+        parts.reverse();
+    }
+    if let Some(keep) = keep {
+        parts.drain(..parts.len().saturating_sub(keep));
+    }
+    let value = parts.join(".");
+
+    if letter.is_ascii_uppercase() {
+        Some(url_escape(&value))
+    } else {
+        Some(value)
+    }
+}
+
+/// Formats the IP address for the `i` macro, IPv6 addresses as dot-separated
+/// nibbles
+fn dotted_ip(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => ip
+            .octets()
+            .iter()
+            .flat_map(|b| [b >> 4, b & 0xf])
+            .map(|nibble| format!("{:x}", nibble))
+            .collect::<Vec<_>>()
+            .join("."),
+    }
+}
+
+fn url_escape(value: &str) -> String {
+    let mut res = String::new();
+    for b in value.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') {
+            res.push(b as char);
+        } else {
+            res.push_str(&format!("%{:02X}", b));
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Examples of https://datatracker.ietf.org/doc/html/rfc7208#section-7.4
+    #[test]
+    fn test_expand() {
+        let ctx = Context {
+            sender: "strong-bad@email.example.com",
+            domain: "email.example.com",
+            ip: "192.0.2.3".parse().unwrap(),
+            helo: "mx.example.org",
+        };
+        let cases = [
+            ("%{s}", "strong-bad@email.example.com"),
+            ("%{o}", "email.example.com"),
+            ("%{d}", "email.example.com"),
+            ("%{d4}", "email.example.com"),
+            ("%{d3}", "email.example.com"),
+            ("%{d2}", "example.com"),
+            ("%{d1}", "com"),
+            ("%{dr}", "com.example.email"),
+            ("%{d2r}", "example.email"),
+            ("%{l}", "strong-bad"),
+            ("%{l-}", "strong.bad"),
+            ("%{lr}", "strong-bad"),
+            ("%{lr-}", "bad.strong"),
+            ("%{l1r-}", "strong"),
+            (
+                "%{ir}.%{v}._spf.%{d2}",
+                "3.2.0.192.in-addr._spf.example.com",
+            ),
+            ("%{lr-}.lp._spf.%{d2}", "bad.strong.lp._spf.example.com"),
+            ("%{h}%%%_%-", "mx.example.org% %20"),
+            ("%{S}", "strong-bad%40email.example.com"),
+        ];
+        for (spec, expected) in cases {
+            assert_eq!(expand(spec, &ctx).unwrap(), expected, "{}", spec);
+        }
+
+        let ctx = Context {
+            ip: "2001:db8::cb01".parse().unwrap(),
+            ..ctx
+        };
+        assert_eq!(
+            expand("%{ir}.%{v}._spf.%{d2}", &ctx).unwrap(),
+            "1.0.b.c.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6._spf.example.com"
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate("%{d}.example.com", false).is_ok());
+        assert!(validate("%{c}", true).is_ok());
+        for spec in ["%{c}", "%{d0}", "%{x}", "%{d", "%a", "%{d2r#}"] {
+            assert!(validate(spec, false).is_err(), "{}", spec);
+        }
+    }
+}
@@ -0,0 +1,569 @@
+/// Evaluation of the Sender Policy Framework, as specified in
+/// https://datatracker.ietf.org/doc/html/rfc7208
+///
+/// The result is the `SPFResult` expected by `Policy::apply`, with the domain
+/// that was checked: the MAIL FROM domain, or the HELO domain for bounces.
+use crate::{dns, Envelope, SPFResult, SpfSource};
+use futures::future::BoxFuture;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+
+mod macros;
+mod record;
+
+use record::{Mechanism, Qualifier};
+
+/// Maximum number of terms causing DNS queries
+// https://datatracker.ietf.org/doc/html/rfc7208#section-4.6.4
+const MAX_DNS_LOOKUPS: usize = 10;
+/// Maximum number of DNS queries returning no records
+const MAX_VOID_LOOKUPS: usize = 2;
+/// Maximum number of MX or PTR names used by a mechanism
+const MAX_NAMES: usize = 10;
+const MAX_DOMAIN_LEN: usize = 253;
+
+/// Result of `check_host()`
+// https://datatracker.ietf.org/doc/html/rfc7208#section-2.6
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Outcome {
+    None,
+    Neutral,
+    Pass,
+    Fail,
+    SoftFail,
+    TempError,
+    PermError,
+}
+
+impl Outcome {
+    fn to_str(self) -> &'static str {
+        match self {
+            Outcome::None => "none",
+            Outcome::Neutral => "neutral",
+            Outcome::Pass => "pass",
+            Outcome::Fail => "fail",
+            Outcome::SoftFail => "softfail",
+            Outcome::TempError => "temperror",
+            Outcome::PermError => "permerror",
+        }
+    }
+}
+
+impl From<Qualifier> for Outcome {
+    fn from(qualifier: Qualifier) -> Self {
+        match qualifier {
+            Qualifier::Pass => Outcome::Pass,
+            Qualifier::Fail => Outcome::Fail,
+            Qualifier::SoftFail => Outcome::SoftFail,
+            Qualifier::Neutral => Outcome::Neutral,
+        }
+    }
+}
+
+/// SPF evaluator, using the same resolver as the policy discovery
+pub struct SpfVerifier {
+    /// Resolver implementing the A, AAAA, MX and PTR lookups
+    pub resolver: Arc<dyn dns::Lookup>,
+    pub logger: slog::Logger,
+}
+
+impl SpfVerifier {
+    pub fn new(resolver: Arc<dyn dns::Lookup>) -> Self {
+        Self {
+            resolver,
+            logger: slog::Logger::root(slog::Discard, slog::o!()),
+        }
+    }
+
+    /// Evaluates SPF for the MAIL FROM identity, or the HELO identity if the
+    /// MAIL FROM is empty (bounces)
+    // https://datatracker.ietf.org/doc/html/rfc7208#section-2.4
+    pub async fn verify(&self, ip: IpAddr, helo: &str, mail_from: &str) -> SPFResult {
+        let mail_from = mail_from.trim_matches(|c| c == '<' || c == '>');
+        let helo = helo.trim_end_matches('.').to_lowercase();
+
+        let sender = match mail_from.rsplit_once('@') {
+            Some(("", domain)) => format!("postmaster@{}", domain),
+            Some(_) => mail_from.to_owned(),
+            None if mail_from.is_empty() => format!("postmaster@{}", helo),
+            // A MAIL FROM without local part is a domain
+            None => format!("postmaster@{}", mail_from),
+        };
+        let domain = sender
+            .rsplit_once('@')
+            .map(|(_, domain)| domain.trim_end_matches('.').to_lowercase())
+            .unwrap_or_default();
+
+        let value = self.check_host(ip, &domain, &sender, &helo).await;
+        SPFResult {
+            domain_used: domain,
+            value: value.to_owned(),
+        }
+    }
+
+    /// Runs `check_host()` and returns the result: none, neutral, pass,
+    /// fail, softfail, temperror or permerror
+    // https://datatracker.ietf.org/doc/html/rfc7208#section-4
+    pub async fn check_host(
+        &self,
+        ip: IpAddr,
+        domain: &str,
+        sender: &str,
+        helo: &str,
+    ) -> &'static str {
+        // IPv4-mapped addresses are evaluated as IPv4
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        let mut evaluation = Evaluation {
+            verifier: self,
+            ip,
+            sender,
+            helo,
+            dns_lookups: 0,
+            void_lookups: 0,
+        };
+        let outcome = evaluation.check_host(domain.to_lowercase()).await;
+        slog::debug!(self.logger, "SPF for {} from {}: {:?}", domain, ip, outcome);
+        outcome.to_str()
+    }
+}
+
+/// Evaluates SPF for the envelope, `None` without the client's IP address
+impl SpfSource for SpfVerifier {
+    fn spf_result<'a>(
+        &'a self,
+        envelope: &'a Envelope<'a>,
+        _raw_message: &'a [u8],
+    ) -> BoxFuture<'a, Option<SPFResult>> {
+        Box::pin(async move {
+            let ip = envelope.client_ip?;
+            Some(self.verify(ip, envelope.helo, envelope.mail_from).await)
+        })
+    }
+}
+
+/// State of an evaluation, shared with the included records for the limits
+struct Evaluation<'a> {
+    verifier: &'a SpfVerifier,
+    ip: IpAddr,
+    sender: &'a str,
+    helo: &'a str,
+    dns_lookups: usize,
+    void_lookups: usize,
+}
+
+impl<'a> Evaluation<'a> {
+    fn resolver(&self) -> &dyn dns::Lookup {
+        self.verifier.resolver.as_ref()
+    }
+
+    fn check_host(&mut self, domain: String) -> BoxFuture<'_, Outcome> {
+        Box::pin(async move {
+            if !is_valid_domain(&domain) {
+                return Outcome::None;
+            }
+
+            let records = match self.resolver().lookup_txt(&domain).await {
+                Ok(records) => records,
+                Err(_) => return Outcome::TempError,
+            };
+            let records: Vec<&String> = records
+                .iter()
+                .filter(|record| record::is_spf_record(record))
+                .collect();
+            let record = match records.as_slice() {
+                [] => return Outcome::None,
+                [record] => *record,
+                _ => return Outcome::PermError,
+            };
+            let record = match record::parse(record) {
+                Ok(record) => record,
+                Err(err) => {
+                    slog::debug!(
+                        self.verifier.logger,
+                        "invalid SPF record for {}: {}",
+                        domain,
+                        err
+                    );
+                    return Outcome::PermError;
+                }
+            };
+
+            for directive in &record.directives {
+                match self.matches(&directive.mechanism, &domain).await {
+                    Ok(true) => return directive.qualifier.into(),
+                    Ok(false) => {}
+                    Err(outcome) => return outcome,
+                }
+            }
+
+            // https://datatracker.ietf.org/doc/html/rfc7208#section-6.1
+            if let Some(redirect) = &record.redirect {
+                let target = match self
+                    .count_lookup()
+                    .and_then(|_| self.expand(redirect, &domain))
+                {
+                    Ok(target) => target,
+                    Err(outcome) => return outcome,
+                };
+                return match self.check_host(target).await {
+                    Outcome::None => Outcome::PermError,
+                    outcome => outcome,
+                };
+            }
+            Outcome::Neutral
+        })
+    }
+
+    /// Checks if the mechanism matches, errors are returned as the outcome of
+    /// the evaluation
+    async fn matches(&mut self, mechanism: &Mechanism, domain: &str) -> Result<bool, Outcome> {
+        match mechanism {
+            Mechanism::All => Ok(true),
+            Mechanism::Ip4(network, len) => Ok(match self.ip {
+                IpAddr::V4(ip) => in_network4(ip, *network, *len),
+                IpAddr::V6(_) => false,
+            }),
+            Mechanism::Ip6(network, len) => Ok(match self.ip {
+                IpAddr::V6(ip) => in_network6(ip, *network, *len),
+                IpAddr::V4(_) => false,
+            }),
+            // https://datatracker.ietf.org/doc/html/rfc7208#section-5.2
+            Mechanism::Include(spec) => {
+                self.count_lookup()?;
+                let target = self.expand(spec, domain)?;
+                match self.check_host(target).await {
+                    Outcome::Pass => Ok(true),
+                    Outcome::Fail | Outcome::SoftFail | Outcome::Neutral => Ok(false),
+                    Outcome::TempError => Err(Outcome::TempError),
+                    Outcome::PermError | Outcome::None => Err(Outcome::PermError),
+                }
+            }
+            Mechanism::A {
+                domain: spec,
+                cidr4,
+                cidr6,
+            } => {
+                self.count_lookup()?;
+                let target = self.target(spec.as_deref(), domain)?;
+                let found = self.match_host(&target, *cidr4, *cidr6).await?;
+                self.check_void(found.is_none())?;
+                Ok(found == Some(true))
+            }
+            // https://datatracker.ietf.org/doc/html/rfc7208#section-5.4
+            Mechanism::Mx {
+                domain: spec,
+                cidr4,
+                cidr6,
+            } => {
+                self.count_lookup()?;
+                let target = self.target(spec.as_deref(), domain)?;
+                let exchanges = self
+                    .resolver()
+                    .lookup_mx(&target)
+                    .await
+                    .map_err(|_| Outcome::TempError)?;
+                self.check_void(exchanges.is_empty())?;
+                if exchanges.len() > MAX_NAMES {
+                    return Err(Outcome::PermError);
+                }
+                for exchange in &exchanges {
+                    if self.match_host(exchange, *cidr4, *cidr6).await? == Some(true) {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            // https://datatracker.ietf.org/doc/html/rfc7208#section-5.5
+            Mechanism::Ptr(spec) => {
+                self.count_lookup()?;
+                let target = self.target(spec.as_deref(), domain)?;
+                let names = match self.resolver().lookup_ptr(self.ip).await {
+                    Ok(names) => names,
+                    // DNS errors make the mechanism fail to match
+                    Err(_) => return Ok(false),
+                };
+                self.check_void(names.is_empty())?;
+                for name in names.iter().take(MAX_NAMES) {
+                    let name = name.trim_end_matches('.').to_lowercase();
+                    let is_target = name == target || name.ends_with(&format!(".{}", target));
+                    if !is_target {
+                        continue;
+                    }
+                    // The name must resolve back to the IP address
+                    if let Ok(Some(true)) = self.match_host(&name, 32, 128).await {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            // https://datatracker.ietf.org/doc/html/rfc7208#section-5.7
+            Mechanism::Exists(spec) => {
+                self.count_lookup()?;
+                let target = self.expand(spec, domain)?;
+                let addresses = self
+                    .resolver()
+                    .lookup_a(&target)
+                    .await
+                    .map_err(|_| Outcome::TempError)?;
+                self.check_void(addresses.is_empty())?;
+                Ok(!addresses.is_empty())
+            }
+        }
+    }
+
+    /// Checks if one of the addresses of the host is in the client's
+    /// network, `None` if the host has no address
+    async fn match_host(&self, host: &str, cidr4: u8, cidr6: u8) -> Result<Option<bool>, Outcome> {
+        let found = match self.ip {
+            IpAddr::V4(ip) => {
+                let addresses = self
+                    .resolver()
+                    .lookup_a(host)
+                    .await
+                    .map_err(|_| Outcome::TempError)?;
+                (!addresses.is_empty())
+                    .then(|| addresses.iter().any(|a| in_network4(ip, *a, cidr4)))
+            }
+            IpAddr::V6(ip) => {
+                let addresses = self
+                    .resolver()
+                    .lookup_aaaa(host)
+                    .await
+                    .map_err(|_| Outcome::TempError)?;
+                (!addresses.is_empty())
+                    .then(|| addresses.iter().any(|a| in_network6(ip, *a, cidr6)))
+            }
+        };
+        Ok(found)
+    }
+
+    fn count_lookup(&mut self) -> Result<(), Outcome> {
+        self.dns_lookups += 1;
+        if self.dns_lookups > MAX_DNS_LOOKUPS {
+            return Err(Outcome::PermError);
+        }
+        Ok(())
+    }
+
+    fn check_void(&mut self, void: bool) -> Result<(), Outcome> {
+        if void {
+            self.void_lookups += 1;
+            if self.void_lookups > MAX_VOID_LOOKUPS {
+                return Err(Outcome::PermError);
+            }
+        }
+        Ok(())
+    }
+
+    /// Domain of the mechanism, the current domain if not specified
+    fn target(&self, spec: Option<&str>, domain: &str) -> Result<String, Outcome> {
+        match spec {
+            Some(spec) => self.expand(spec, domain),
+            None => Ok(domain.to_owned()),
+        }
+    }
+
+    fn expand(&self, spec: &str, domain: &str) -> Result<String, Outcome> {
+        let ctx = macros::Context {
+            sender: self.sender,
+            domain,
+            ip: self.ip,
+            helo: self.helo,
+        };
+        let expanded = macros::expand(spec, &ctx).map_err(|_| Outcome::PermError)?;
+        Ok(truncate_domain(expanded.trim_end_matches('.')).to_lowercase())
+    }
+}
+
+/// Removes the leftmost labels until the domain is at most 253 characters
+// https://datatracker.ietf.org/doc/html/rfc7208#section-7.3
+fn truncate_domain(mut domain: &str) -> &str {
+    while domain.len() > MAX_DOMAIN_LEN {
+        domain = match domain.split_once('.') {
+            Some((_, rest)) => rest,
+            None => return "",
+        };
+    }
+    domain
+}
+
+/// A domain must have at least two labels, none empty or longer than 63
+/// characters
+// https://datatracker.ietf.org/doc/html/rfc7208#section-4.3
+fn is_valid_domain(domain: &str) -> bool {
+    domain.len() <= MAX_DOMAIN_LEN
+        && domain.contains('.')
+        && domain
+            .split('.')
+            .all(|label| !label.is_empty() && label.len() <= 63)
+}
+
+fn in_network4(ip: Ipv4Addr, network: Ipv4Addr, len: u8) -> bool {
+    let mask = u32::MAX.checked_shl(32 - len as u32).unwrap_or(0);
+    u32::from(ip) & mask == u32::from(network) & mask
+}
+
+fn in_network6(ip: Ipv6Addr, network: Ipv6Addr, len: u8) -> bool {
+    let mask = u128::MAX.checked_shl(128 - len as u32).unwrap_or(0);
+    u128::from(ip) & mask == u128::from(network) & mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::testing::TestResolver;
+
+    async fn check(resolver: TestResolver, ip: &str) -> &'static str {
+        let verifier = SpfVerifier::new(resolver.build());
+        verifier
+            .check_host(
+                ip.parse().unwrap(),
+                "example.com",
+                "alice@example.com",
+                "mx.example.com",
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_mechanisms() {
+        let resolver = || {
+            TestResolver::default()
+                .txt(
+                    "example.com",
+                    "v=spf1 ip4:192.0.2.0/24 a:mail.example.com mx/24 ptr \
+                     include:_spf.example.net -ip6:2001:db8::/32 ~all",
+                )
+                .ip("mail.example.com", "198.51.100.1")
+                .mx("example.com", "mx.example.com")
+                .ip("mx.example.com", "203.0.113.1")
+                .ptr("198.51.100.99", "host.example.com")
+                .ip("host.example.com", "198.51.100.99")
+                .txt("_spf.example.net", "v=spf1 ip6:2001:db8::1 -all")
+        };
+
+        assert_eq!(check(resolver(), "192.0.2.7").await, "pass");
+        assert_eq!(check(resolver(), "198.51.100.1").await, "pass");
+        assert_eq!(check(resolver(), "203.0.113.200").await, "pass");
+        assert_eq!(check(resolver(), "198.51.100.99").await, "pass");
+        assert_eq!(check(resolver(), "2001:db8::1").await, "pass");
+        assert_eq!(check(resolver(), "2001:db8::2").await, "fail");
+        assert_eq!(check(resolver(), "::ffff:192.0.2.1").await, "pass");
+        assert_eq!(check(resolver(), "10.0.0.1").await, "softfail");
+    }
+
+    #[tokio::test]
+    async fn test_results() {
+        assert_eq!(check(TestResolver::default(), "192.0.2.1").await, "none");
+        assert_eq!(
+            check(
+                TestResolver::default().txt("example.com", "v=spf1 ip4:10.0.0.1"),
+                "192.0.2.1"
+            )
+            .await,
+            "neutral"
+        );
+        assert_eq!(
+            check(
+                TestResolver::default()
+                    .txt("example.com", "v=spf1 -all")
+                    .txt("example.com", "v=spf1 +all"),
+                "192.0.2.1"
+            )
+            .await,
+            "permerror"
+        );
+        assert_eq!(
+            check(
+                TestResolver::default().txt("example.com", "v=spf1 hein -all"),
+                "192.0.2.1"
+            )
+            .await,
+            "permerror"
+        );
+        // The target of a redirect must have a record
+        assert_eq!(
+            check(
+                TestResolver::default().txt("example.com", "v=spf1 redirect=example.net"),
+                "192.0.2.1"
+            )
+            .await,
+            "permerror"
+        );
+        assert_eq!(
+            check(
+                TestResolver::default()
+                    .txt("example.com", "v=spf1 redirect=%{l}.example.net")
+                    .txt("alice.example.net", "v=spf1 ?all"),
+                "192.0.2.1"
+            )
+            .await,
+            "neutral"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        // Each include counts as a lookup, the 11th fails
+        let mut resolver = TestResolver::default();
+        for i in 0..11 {
+            resolver = resolver.txt(
+                &format!("{}.example.com", i),
+                &format!("v=spf1 include:{}.example.com", i + 1),
+            );
+        }
+        let resolver = resolver
+            .txt("example.com", "v=spf1 include:0.example.com")
+            .txt("11.example.com", "v=spf1 +all");
+        assert_eq!(check(resolver, "192.0.2.1").await, "permerror");
+
+        // Only two lookups can return no records
+        let resolver = TestResolver::default().txt(
+            "example.com",
+            "v=spf1 a:a.example.com exists:b.example.com mx:c.example.com +all",
+        );
+        assert_eq!(check(resolver, "192.0.2.1").await, "permerror");
+        let resolver = TestResolver::default().txt(
+            "example.com",
+            "v=spf1 a:a.example.com mx:c.example.com +all",
+        );
+        assert_eq!(check(resolver, "192.0.2.1").await, "pass");
+    }
+
+    #[tokio::test]
+    async fn test_verify() {
+        let verifier = SpfVerifier::new(
+            TestResolver::default()
+                .txt("example.com", "v=spf1 -all")
+                .txt("mx.example.net", "v=spf1 a -all")
+                .ip("mx.example.net", "192.0.2.1")
+                .build(),
+        );
+        let ip = "192.0.2.1".parse().unwrap();
+
+        let res = verifier
+            .verify(ip, "mx.example.net", "<bounce@Example.com>")
+            .await;
+        assert_eq!(res.domain_used, "example.com");
+        assert_eq!(res.value, "fail");
+
+        // Bounces use the HELO identity
+        let res = verifier.verify(ip, "mx.example.net", "<>").await;
+        assert_eq!(res.domain_used, "mx.example.net");
+        assert_eq!(res.value, "pass");
+    }
+
+    #[test]
+    fn test_in_network() {
+        let ip = "192.0.2.200".parse().unwrap();
+        assert!(in_network4(ip, "192.0.2.0".parse().unwrap(), 24));
+        assert!(!in_network4(ip, "192.0.2.0".parse().unwrap(), 25));
+        assert!(in_network4(ip, "10.0.0.0".parse().unwrap(), 0));
+        let ip = "2001:db8::1".parse().unwrap();
+        assert!(in_network6(ip, "2001:db8::".parse().unwrap(), 32));
+        assert!(!in_network6(ip, "2001:db9::".parse().unwrap(), 32));
+    }
+}
@@ -0,0 +1,281 @@
+/// Parsing of SPF records
+// https://datatracker.ietf.org/doc/html/rfc7208#section-4.6
+use super::macros;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum Qualifier {
+    Pass,
+    Fail,
+    SoftFail,
+    Neutral,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum Mechanism {
+    All,
+    Include(String),
+    A {
+        domain: Option<String>,
+        cidr4: u8,
+        cidr6: u8,
+    },
+    Mx {
+        domain: Option<String>,
+        cidr4: u8,
+        cidr6: u8,
+    },
+    Ptr(Option<String>),
+    Ip4(Ipv4Addr, u8),
+    Ip6(Ipv6Addr, u8),
+    Exists(String),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct Directive {
+    pub(crate) qualifier: Qualifier,
+    pub(crate) mechanism: Mechanism,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub(crate) struct Record {
+    pub(crate) directives: Vec<Directive>,
+    pub(crate) redirect: Option<String>,
+}
+
+/// Checks if the TXT record is a SPF record
+// https://datatracker.ietf.org/doc/html/rfc7208#section-4.5
+pub(crate) fn is_spf_record(record: &str) -> bool {
+    let version = record.get(..6).unwrap_or_default();
+    version.eq_ignore_ascii_case("v=spf1") && matches!(record.as_bytes().get(6), None | Some(b' '))
+}
+
+/// Parses the record, any syntax error makes the whole record invalid
+pub(crate) fn parse(record: &str) -> Result<Record, String> {
+    let mut res = Record::default();
+    let mut exp = false;
+
+    for term in record.split(' ').skip(1).filter(|term| !term.is_empty()) {
+        if let Some((name, value)) = parse_modifier(term) {
+            let name = name.to_lowercase();
+            match name.as_str() {
+                "redirect" | "exp" => {
+                    let duplicate = match name.as_str() {
+                        "redirect" => res.redirect.is_some(),
+                        _ => exp,
+                    };
+                    if duplicate {
+                        return Err(format!("duplicate modifier: {}", name));
+                    }
+                    let value = domain_spec(value)?;
+                    if name == "redirect" {
+                        res.redirect = Some(value);
+                    } else {
+                        // The explanation isn't used but must be valid
+                        exp = true;
+                    }
+                }
+                // Unknown modifiers are ignored
+                _ => {
+                    macros::validate(value, true)?;
+                }
+            }
+            continue;
+        }
+        res.directives.push(parse_directive(term)?);
+    }
+
+    Ok(res)
+}
+
+/// Returns the name and value of a modifier, `None` if the term is a
+/// directive
+fn parse_modifier(term: &str) -> Option<(&str, &str)> {
+    let (name, value) = term.split_once('=')?;
+    let mut chars = name.chars();
+    let valid_name = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+    valid_name.then_some((name, value))
+}
+
+fn parse_directive(term: &str) -> Result<Directive, String> {
+    let (qualifier, mechanism) = match term.as_bytes()[0] {
+        b'+' => (Qualifier::Pass, &term[1..]),
+        b'-' => (Qualifier::Fail, &term[1..]),
+        b'~' => (Qualifier::SoftFail, &term[1..]),
+        b'?' => (Qualifier::Neutral, &term[1..]),
+        _ => (Qualifier::Pass, term),
+    };
+
+    let end = mechanism.find([':', '/']).unwrap_or(mechanism.len());
+    let (name, arg) = mechanism.split_at(end);
+
+    let mechanism = match name.to_lowercase().as_str() {
+        "all" if arg.is_empty() => Mechanism::All,
+        "include" => Mechanism::Include(required_domain_spec(arg)?),
+        "exists" => Mechanism::Exists(required_domain_spec(arg)?),
+        "ptr" => Mechanism::Ptr(optional_domain_spec(arg)?),
+        "a" | "mx" => {
+            let (arg, cidr4, cidr6) = parse_dual_cidr(arg)?;
+            let domain = optional_domain_spec(arg)?;
+            if name.eq_ignore_ascii_case("a") {
+                Mechanism::A {
+                    domain,
+                    cidr4,
+                    cidr6,
+                }
+            } else {
+                Mechanism::Mx {
+                    domain,
+                    cidr4,
+                    cidr6,
+                }
+            }
+        }
+        "ip4" => {
+            let (ip, len) = parse_network(arg, 32)?;
+            Mechanism::Ip4(ip.parse().map_err(|_| invalid(term))?, len)
+        }
+        "ip6" => {
+            let (ip, len) = parse_network(arg, 128)?;
+            Mechanism::Ip6(ip.parse().map_err(|_| invalid(term))?, len)
+        }
+        _ => return Err(invalid(term)),
+    };
+
+    Ok(Directive {
+        qualifier,
+        mechanism,
+    })
+}
+
+fn invalid(term: &str) -> String {
+    format!("invalid term: {}", term)
+}
+
+/// Parses `:ip/len` of the `ip4` and `ip6` mechanisms
+fn parse_network(arg: &str, max_len: u8) -> Result<(&str, u8), String> {
+    let arg = arg.strip_prefix(':').ok_or_else(|| invalid(arg))?;
+    match arg.split_once('/') {
+        Some((ip, len)) => Ok((ip, parse_cidr(len, max_len)?)),
+        None => Ok((arg, max_len)),
+    }
+}
+
+/// Parses the optional `/cidr4//cidr6` suffix of the `a` and `mx`
+/// mechanisms, returning the remaining argument
+fn parse_dual_cidr(arg: &str) -> Result<(&str, u8, u8), String> {
+    let is_cidr = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+
+    let (arg, cidr6) = match arg.rsplit_once("//") {
+        Some((rest, len)) if is_cidr(len) => (rest, parse_cidr(len, 128)?),
+        _ => (arg, 128),
+    };
+    let (arg, cidr4) = match arg.rsplit_once('/') {
+        Some((rest, len)) if is_cidr(len) => (rest, parse_cidr(len, 32)?),
+        _ => (arg, 32),
+    };
+    Ok((arg, cidr4, cidr6))
+}
+
+fn parse_cidr(len: &str, max_len: u8) -> Result<u8, String> {
+    match len.parse::<u8>() {
+        // Leading zeros aren't allowed
+        Ok(v) if v <= max_len && !(len.len() > 1 && len.starts_with('0')) => Ok(v),
+        _ => Err(format!("invalid prefix length: {}", len)),
+    }
+}
+
+fn optional_domain_spec(arg: &str) -> Result<Option<String>, String> {
+    if arg.is_empty() {
+        return Ok(None);
+    }
+    required_domain_spec(arg).map(Some)
+}
+
+fn required_domain_spec(arg: &str) -> Result<String, String> {
+    let spec = arg.strip_prefix(':').ok_or_else(|| invalid(arg))?;
+    domain_spec(spec)
+}
+
+fn domain_spec(spec: &str) -> Result<String, String> {
+    if spec.is_empty() {
+        return Err("empty domain".to_owned());
+    }
+    macros::validate(spec, false)?;
+    Ok(spec.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_spf_record() {
+        assert!(is_spf_record("v=spf1 -all"));
+        assert!(is_spf_record("V=SPF1"));
+        assert!(!is_spf_record("v=spf10 -all"));
+        assert!(!is_spf_record("v=DMARC1; p=none"));
+    }
+
+    #[test]
+    fn test_parse() {
+        let record = parse(
+            "v=spf1 ip4:192.0.2.0/24 ip6:2001:db8::/32 a -a:%{d}.example.com/28//64 \
+             mx:example.net ~include:_spf.example.com ?exists:%{i}.x.example ptr \
+             foo=bar redirect=example.org",
+        )
+        .unwrap();
+
+        assert_eq!(
+            record.directives[0].mechanism,
+            Mechanism::Ip4("192.0.2.0".parse().unwrap(), 24)
+        );
+        assert_eq!(
+            record.directives[1].mechanism,
+            Mechanism::Ip6("2001:db8::".parse().unwrap(), 32)
+        );
+        assert_eq!(
+            record.directives[2].mechanism,
+            Mechanism::A {
+                domain: None,
+                cidr4: 32,
+                cidr6: 128
+            }
+        );
+        assert_eq!(
+            record.directives[3],
+            Directive {
+                qualifier: Qualifier::Fail,
+                mechanism: Mechanism::A {
+                    domain: Some("%{d}.example.com".to_owned()),
+                    cidr4: 28,
+                    cidr6: 64
+                }
+            }
+        );
+        assert_eq!(record.directives[5].qualifier, Qualifier::SoftFail);
+        assert_eq!(record.directives[6].qualifier, Qualifier::Neutral);
+        assert_eq!(record.directives[7].mechanism, Mechanism::Ptr(None));
+        assert_eq!(record.directives.len(), 8);
+        assert_eq!(record.redirect.as_deref(), Some("example.org"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for record in [
+            "v=spf1 hein",
+            "v=spf1 ip4:192.0.2.0/33",
+            "v=spf1 ip4:2001:db8::1",
+            "v=spf1 a/024",
+            "v=spf1 include",
+            "v=spf1 include:",
+            "v=spf1 all:example.com",
+            "v=spf1 exists:%{z}",
+            "v=spf1 include:%{é}.example.net -all",
+            "v=spf1 redirect=a.com redirect=b.com",
+        ] {
+            assert!(parse(record).is_err(), "{}", record);
+        }
+    }
+}
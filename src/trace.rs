@@ -0,0 +1,103 @@
+/// Structured trace of the DMARC evaluation
+use crate::policy::{Alignement, ReceiverAction};
+
+/// Trace of the evaluation of an author domain
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EvaluationTrace {
+    /// Author domain
+    pub from_domain: String,
+    /// Domain where the policy was found, if any
+    pub policy_domain: Option<String>,
+    pub organizational_domain: String,
+    /// Whether the policy is a local override
+    pub overridden: bool,
+    /// Whether the policy was found at a Public Suffix Domain
+    pub from_psd: bool,
+    /// Whether the author domain exists, only checked for the `np` tag
+    pub from_domain_exists: Option<bool>,
+    /// Trace of the policy application, if a policy was found
+    pub policy: Option<PolicyTrace>,
+}
+
+/// Trace of `Policy::apply_with_trace`
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PolicyTrace {
+    /// Action requested for the message, after the `np` and `t` tags
+    pub action: ReceiverAction,
+    /// Whether the `np` tag replaced the action
+    pub np_applied: bool,
+    /// Whether the testing mode downgraded the action
+    pub testing: bool,
+    pub sampling: SamplingTrace,
+    /// DKIM alignment check, also done when the `pct` tag skipped the policy
+    pub dkim: Option<AlignmentTrace>,
+    /// SPF alignment check, also done when the `pct` tag skipped the policy
+    pub spf: Option<AlignmentTrace>,
+    /// DMARC result: pass, fail or neutral
+    pub result: String,
+    pub disposition: ReceiverAction,
+}
+
+/// Sampling decision based on the `pct` tag
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SamplingTrace {
+    pub pct: usize,
+    /// Whether the policy applies to the message
+    pub applied: bool,
+}
+
+/// Identifier alignment check
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AlignmentTrace {
+    pub mode: Alignement,
+    pub from_domain: String,
+    pub from_org_domain: Option<String>,
+    /// Domain authenticated by DKIM (`d=`) or SPF
+    pub domain: String,
+    pub org_domain: Option<String>,
+    pub aligned: bool,
+    /// Result of the authentication mechanism
+    pub result: String,
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serialize() {
+        let trace = PolicyTrace {
+            action: ReceiverAction::Quarantine,
+            np_applied: false,
+            testing: true,
+            sampling: SamplingTrace {
+                pct: 100,
+                applied: true,
+            },
+            dkim: Some(AlignmentTrace {
+                mode: Alignement::Relaxed,
+                from_domain: "a.com".to_owned(),
+                from_org_domain: Some("a.com".to_owned()),
+                domain: "b.com".to_owned(),
+                org_domain: Some("b.com".to_owned()),
+                aligned: false,
+                result: "pass".to_owned(),
+            }),
+            spf: None,
+            result: "fail".to_owned(),
+            disposition: ReceiverAction::Quarantine,
+        };
+
+        let json = serde_json::to_value(&trace).unwrap();
+        assert_eq!(json["action"], "quarantine");
+        assert_eq!(json["dkim"]["mode"], "r");
+        assert_eq!(json["spf"], serde_json::Value::Null);
+
+        let back: PolicyTrace = serde_json::from_value(json).unwrap();
+        assert_eq!(back, trace);
+    }
+}
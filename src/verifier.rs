@@ -0,0 +1,362 @@
+/// Long-lived DMARC verifier
+use crate::{
+    discover_policy_with_resolver, dns, extract_from_domains, DMARCError, DMARCResult, Discovery,
+    DiscoveryOptions, Envelope, EvaluationTrace, FromHeaderOptions, MessageVerdict, Policy,
+    PolicyContext, RandomSampler, SPFResult, Sampler, SpfSource,
+};
+use std::net::IpAddr;
+use std::sync::Arc;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Verifier owning the resolver and the configuration, meant to be set up
+/// once and shared by the application
+///
+/// Unlike `load_policy` the resolver is reused between lookups, keeping its
+/// cache and connections.
+pub struct DmarcVerifier {
+    /// Resolver used for the policy discovery
+    pub resolver: Arc<dyn dns::Lookup>,
+    /// Options of the policy discovery (mode, Public Suffix List, PSD DMARC
+    /// and local policy overrides)
+    pub discovery_options: DiscoveryOptions,
+    /// Decides if the policies apply based on their `pct` tag
+    pub sampler: Arc<dyn Sampler>,
+    /// Handling of the From headers by `verify_message`
+    pub from_header_options: FromHeaderOptions,
+    /// SPF result used by `verify_message`. With the `spf` feature it's
+    /// evaluated with the resolver, otherwise SPF isn't checked.
+    pub spf_source: Arc<dyn SpfSource>,
+}
+
+impl DmarcVerifier {
+    /// Creates a verifier with the default configuration
+    pub fn new(resolver: Arc<dyn dns::Lookup>) -> Self {
+        #[cfg(feature = "spf")]
+        let spf_source = Arc::new(crate::spf::SpfVerifier::new(Arc::clone(&resolver)));
+        #[cfg(not(feature = "spf"))]
+        let spf_source = Arc::new(crate::NoSpf);
+
+        Self {
+            resolver,
+            discovery_options: DiscoveryOptions::default(),
+            sampler: Arc::new(RandomSampler),
+            from_header_options: FromHeaderOptions::default(),
+            spf_source,
+        }
+    }
+
+    /// Creates a verifier using the system's DNS configuration
+    pub fn from_system_conf() -> Result<Self, DMARCError> {
+        let resolver = TokioAsyncResolver::tokio_from_system_conf().map_err(|err| {
+            DMARCError::UnknownInternalError(format!("failed to create DNS resolver: {}", err))
+        })?;
+        Ok(Self::new(dns::from_tokio_resolver(resolver)))
+    }
+
+    /// Load the DMARC policy for the domain
+    pub async fn load_policy<'a>(
+        &'a self,
+        logger: &'a slog::Logger,
+        from_domain: &'a str,
+    ) -> Result<Option<Policy>, DMARCError> {
+        Ok(self.discover(logger, from_domain).await?.policy)
+    }
+
+    /// Discover the DMARC policy and the Organizational Domain for the domain
+    pub async fn discover<'a>(
+        &'a self,
+        logger: &'a slog::Logger,
+        from_domain: &'a str,
+    ) -> Result<Discovery, DMARCError> {
+        discover_policy_with_resolver(
+            Arc::clone(&self.resolver),
+            logger,
+            from_domain,
+            &self.discovery_options,
+        )
+        .await
+    }
+
+    /// Evaluate DMARC for a message that has one or more author domains
+    ///
+    /// See `evaluate_author_domains`.
+    pub async fn evaluate<'a>(
+        &'a self,
+        logger: &'a slog::Logger,
+        from_domains: &'a [String],
+        dkim_result: &'a cfdkim::DKIMResult,
+        spf_result: &'a SPFResult,
+    ) -> Result<DMARCResult, DMARCError> {
+        Ok(self
+            .evaluate_with_trace(logger, from_domains, dkim_result, spf_result)
+            .await?
+            .0)
+    }
+
+    /// Same as `evaluate` but also returns the trace of the evaluation of
+    /// each author domain
+    pub async fn evaluate_with_trace<'a>(
+        &'a self,
+        logger: &'a slog::Logger,
+        from_domains: &'a [String],
+        dkim_result: &'a cfdkim::DKIMResult,
+        spf_result: &'a SPFResult,
+    ) -> Result<(DMARCResult, Vec<EvaluationTrace>), DMARCError> {
+        let mut result = DMARCResult::none();
+        let mut traces = vec![];
+
+        for from_domain in from_domains {
+            let discovery = self.discover(logger, from_domain).await?;
+            let mut trace = EvaluationTrace {
+                from_domain: from_domain.clone(),
+                policy_domain: discovery.policy_domain,
+                organizational_domain: discovery.organizational_domain,
+                overridden: discovery.overridden,
+                from_psd: discovery.from_psd,
+                from_domain_exists: None,
+                policy: None,
+            };
+
+            let domain_result = if let Some(policy) = discovery.policy {
+                if policy.np.is_some() {
+                    trace.from_domain_exists =
+                        Some(self.resolver.domain_exists(from_domain).await?);
+                }
+                let mut ctx = PolicyContext::new(
+                    from_domain,
+                    dkim_result.clone(),
+                    spf_result.clone(),
+                    logger,
+                )
+                .with_org_domain_resolver(&self.discovery_options.org_domain_resolver)
+                .with_sampler(self.sampler.as_ref());
+                ctx.from_domain_exists = trace.from_domain_exists;
+                let (domain_result, policy_trace) = policy.apply_with_trace(&ctx);
+                trace.policy = Some(policy_trace);
+                domain_result
+            } else {
+                DMARCResult::none()
+            };
+
+            if domain_result.is_more_restrictive_than(&result) {
+                result = domain_result;
+            }
+            traces.push(trace);
+        }
+
+        Ok((result, traces))
+    }
+
+    /// Verifies a message in the RFC5322 format received from the SMTP
+    /// client: extracts the author domains, verifies their DKIM signatures
+    /// with the resolver, gets the SPF result from the `spf_source` and
+    /// evaluates the DMARC policies
+    pub async fn verify_message<'a>(
+        &'a self,
+        logger: &'a slog::Logger,
+        raw_message: &'a [u8],
+        client_ip: Option<IpAddr>,
+        helo: &'a str,
+        mail_from: &'a str,
+    ) -> Result<MessageVerdict, DMARCError> {
+        let email = mailparse::parse_mail(raw_message)
+            .map_err(|err| DMARCError::InvalidMessage(err.to_string()))?;
+        let from_domains = extract_from_domains(raw_message, &self.from_header_options)?;
+
+        let envelope = Envelope {
+            client_ip,
+            helo,
+            mail_from,
+        };
+        let spf_result = self.spf_source.spf_result(&envelope, raw_message).await;
+        let evaluated_spf_result = spf_result.clone().unwrap_or_else(|| SPFResult {
+            domain_used: envelope.spf_domain(),
+            value: "none".to_owned(),
+        });
+
+        let dkim_resolver = dns::to_dkim_resolver(Arc::clone(&self.resolver));
+        let mut verdict = MessageVerdict {
+            from_domains: from_domains.clone(),
+            dkim_results: vec![],
+            spf_result,
+            mail_from: envelope.mail_from_address().to_owned(),
+            result: DMARCResult::none(),
+            result_domain: from_domains[0].clone(),
+            traces: vec![],
+        };
+
+        for from_domain in &from_domains {
+            // Only the signatures of the author domain are verified
+            let dkim_result = cfdkim::verify_email_with_resolver(
+                logger,
+                from_domain,
+                &email,
+                Arc::clone(&dkim_resolver),
+            )
+            .await
+            .unwrap_or_else(|err| cfdkim::DKIMResult::fail(err, from_domain.clone()));
+
+            let (result, mut traces) = self
+                .evaluate_with_trace(
+                    logger,
+                    std::slice::from_ref(from_domain),
+                    &dkim_result,
+                    &evaluated_spf_result,
+                )
+                .await?;
+            if result.is_more_restrictive_than(&verdict.result) {
+                verdict.result = result;
+                verdict.result_domain = from_domain.clone();
+            }
+            verdict.dkim_results.push(dkim_result);
+            verdict.traces.append(&mut traces);
+        }
+
+        Ok(verdict)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::testing::TestResolver;
+    use crate::ReceiverAction;
+
+    struct NeverSampler;
+    impl Sampler for NeverSampler {
+        fn should_apply(&self, _pct: usize) -> bool {
+            false
+        }
+    }
+
+    fn logger() -> slog::Logger {
+        slog::Logger::root(slog::Discard, slog::o!())
+    }
+
+    fn failing_auth() -> (cfdkim::DKIMResult, SPFResult) {
+        (
+            cfdkim::DKIMResult::neutral("a.com".to_owned()),
+            SPFResult {
+                domain_used: "b.com".to_string(),
+                value: "pass".to_string(),
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn test_verifier_evaluate() {
+        let resolver = TestResolver::default()
+            .txt("_dmarc.a.com", "v=DMARC1; p=reject;")
+            .build();
+        let verifier = DmarcVerifier::new(resolver);
+        let (dkim_result, spf_result) = failing_auth();
+
+        let res = verifier
+            .evaluate(&logger(), &["a.com".to_owned()], &dkim_result, &spf_result)
+            .await
+            .unwrap();
+        assert_eq!(res.to_str(), "fail");
+        assert!(res.should_reject());
+
+        let policy = verifier.load_policy(&logger(), "a.com").await.unwrap();
+        assert_eq!(policy.unwrap().action, ReceiverAction::Reject);
+    }
+
+    #[tokio::test]
+    async fn test_verifier_sampler() {
+        let resolver = TestResolver::default()
+            .txt("_dmarc.a.com", "v=DMARC1; p=reject;")
+            .build();
+        let mut verifier = DmarcVerifier::new(resolver);
+        verifier.sampler = Arc::new(NeverSampler);
+        let (dkim_result, spf_result) = failing_auth();
+
+        let res = verifier
+            .evaluate(&logger(), &["a.com".to_owned()], &dkim_result, &spf_result)
+            .await
+            .unwrap();
+        assert_eq!(res.to_str(), "neutral");
+        assert!(!res.should_reject());
+    }
+
+    #[tokio::test]
+    async fn test_verifier_overrides() {
+        let resolver = TestResolver::default()
+            .txt("_dmarc.a.com", "v=DMARC1; p=reject;")
+            .build();
+        let mut verifier = DmarcVerifier::new(resolver);
+        verifier
+            .discovery_options
+            .overrides
+            .insert("a.com".to_owned(), Policy::new(ReceiverAction::None));
+
+        let discovery = verifier.discover(&logger(), "sub.a.com").await.unwrap();
+        assert!(discovery.overridden);
+        assert_eq!(discovery.policy.unwrap().action, ReceiverAction::None);
+    }
+
+    #[tokio::test]
+    async fn test_verifier_evaluate_with_trace() {
+        let resolver = TestResolver::default()
+            .txt("_dmarc.a.com", "v=DMARC1; p=reject;")
+            .build();
+        let verifier = DmarcVerifier::new(resolver);
+        let (dkim_result, spf_result) = failing_auth();
+        let from_domains = ["sub.a.com".to_owned(), "c.com".to_owned()];
+
+        let (res, traces) = verifier
+            .evaluate_with_trace(&logger(), &from_domains, &dkim_result, &spf_result)
+            .await
+            .unwrap();
+        assert_eq!(res.to_str(), "fail");
+        assert_eq!(traces.len(), 2);
+
+        assert_eq!(traces[0].policy_domain.as_deref(), Some("a.com"));
+        assert_eq!(traces[0].organizational_domain, "a.com");
+        let policy = traces[0].policy.as_ref().unwrap();
+        assert_eq!(policy.disposition, ReceiverAction::Reject);
+        assert!(!policy.spf.as_ref().unwrap().aligned);
+
+        assert_eq!(traces[1].policy_domain, None);
+        assert!(traces[1].policy.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_verify_message() {
+        let resolver = TestResolver::default()
+            .txt("_dmarc.a.com", "v=DMARC1; p=reject;")
+            .build();
+        let mut verifier = DmarcVerifier::new(resolver);
+        verifier.spf_source = Arc::new(crate::ReceivedSpfHeader);
+        let raw = b"Received-SPF: pass (mx.local)\r\n\
+                    From: Alice <alice@sub.a.com>\r\n\
+                    Subject: Hello\r\n\r\nHello\r\n";
+
+        let verdict = verifier
+            .verify_message(&logger(), raw, None, "mx.b.com", "<bounce@b.com>")
+            .await
+            .unwrap();
+        assert_eq!(verdict.from_domains, vec!["sub.a.com".to_owned()]);
+        assert_eq!(verdict.spf_result.as_ref().unwrap().domain_used, "b.com");
+        assert_eq!(verdict.disposition(), ReceiverAction::Reject);
+        assert_eq!(verdict.traces.len(), 1);
+        assert_eq!(
+            verdict.authentication_results("mx.local"),
+            "mx.local; dkim=neutral header.d=sub.a.com; spf=pass smtp.mailfrom=bounce@b.com; \
+             dmarc=fail (p=reject dis=reject) header.from=sub.a.com"
+        );
+
+        verifier.spf_source = Arc::new(crate::NoSpf);
+        let verdict = verifier
+            .verify_message(&logger(), raw, None, "mx.b.com", "")
+            .await
+            .unwrap();
+        assert!(verdict.spf_result.is_none());
+        assert_eq!(verdict.authentication_methods().len(), 2);
+
+        let res = verifier
+            .verify_message(&logger(), b"Subject: Hello\r\n\r\n", None, "", "")
+            .await;
+        assert!(matches!(res, Err(DMARCError::InvalidFromHeader(_))));
+    }
+}